@@ -1,6 +1,8 @@
 //! Module containing the low level [`JobDesc `] builder
 
-use embedded_ci_common::job::{JobDesc, TaskDesc};
+use embedded_ci_common::job::{
+    ExecutionMode, ExitCriterion, ImageDesc, ImageFormat, JobDesc, RttChannel, RttInput, TaskDesc,
+};
 pub use embedded_ci_common::*;
 
 /// Possible errors produced by the [`JobDescBuilder`]
@@ -15,9 +17,9 @@ pub enum Error {
     /// No run_ons have been specified for a task
     #[error("No run_ons have been specified for a task")]
     NoRunOns,
-    /// No ELF has been specified for a task
-    #[error("No ELF has been specified for a task")]
-    NoElf,
+    /// No image has been specified for a task
+    #[error("No image has been specified for a task")]
+    NoImage,
     /// No tasks has been specified for a job
     #[error("No tasks has been specified for a job")]
     NoTasks,
@@ -70,22 +72,71 @@ impl JobDescBuilder {
 /// Low level [`TaskDesc`]s builder without any coherency checking capabilities
 pub struct TaskDescBuilder {
     parent_builder: JobDescBuilder,
-    elf: Option<Vec<u8>>,
+    images: Vec<ImageDesc>,
     run_ons: Vec<RunOn>,
+    rtt_channels: Option<Vec<RttChannel>>,
+    rtt_input: Option<RttInput>,
+    exit_criteria: Vec<ExitCriterion>,
+    inactivity_timeout_secs: Option<u32>,
+    entry_symbol: Option<String>,
+    skip_run_to_entry: bool,
+    no_rtt: bool,
+    execution_mode: ExecutionMode,
 }
 
 impl TaskDescBuilder {
     fn new(parent_builder: JobDescBuilder) -> Self {
         Self {
             parent_builder,
-            elf: None,
+            images: Vec::new(),
             run_ons: Vec::new(),
+            rtt_channels: None,
+            rtt_input: None,
+            exit_criteria: Vec::new(),
+            inactivity_timeout_secs: None,
+            entry_symbol: None,
+            skip_run_to_entry: false,
+            no_rtt: false,
+            execution_mode: ExecutionMode::Flash,
         }
     }
 
     /// Set the ELF executable that is supposed to be run on targets matching specified [`RunOn`]s
+    ///
+    /// Replaces the images added before, see [`TaskDescBuilder::add_image`] to flash several.
     pub fn elf_executable(mut self, elf: Vec<u8>) -> Self {
-        self.elf = Some(elf);
+        self.images.clear();
+        self.add_image(elf, ImageFormat::Elf)
+    }
+
+    /// Add an image, in any of the supported formats, flashed on targets matching specified
+    /// [`RunOn`]s
+    ///
+    /// The first image added is the primary one, whose symbols drive the run, the next ones are
+    /// flashed along with it, such as a bootloader or a configuration blob. Images are flashed in
+    /// the order they are added.
+    pub fn add_image(mut self, image: Vec<u8>, format: ImageFormat) -> Self {
+        let primary = self.images.is_empty();
+        self.images.push(ImageDesc {
+            binary_b64: base64::encode(image),
+            format,
+            primary,
+        });
+        self
+    }
+
+    /// Add the primary image of the task, whose symbols drive the run
+    ///
+    /// The images added before are flashed along with it.
+    pub fn add_primary_image(mut self, image: Vec<u8>, format: ImageFormat) -> Self {
+        for image in self.images.iter_mut() {
+            image.primary = false;
+        }
+        self.images.push(ImageDesc {
+            binary_b64: base64::encode(image),
+            format,
+            primary: true,
+        });
         self
     }
 
@@ -95,14 +146,73 @@ impl TaskDescBuilder {
         self
     }
 
+    /// Capture the given RTT up channel, by default all of them are captured.
+    pub fn rtt_channel(mut self, channel: RttChannel) -> Self {
+        self.rtt_channels.get_or_insert_with(Vec::new).push(channel);
+        self
+    }
+
+    /// Set the input script sent to the firmware over an RTT down channel
+    pub fn rtt_input(mut self, input: RttInput) -> Self {
+        self.rtt_input = Some(input);
+        self
+    }
+
+    /// Add a condition ending the run early, the first one met decides the outcome
+    pub fn exit_criterion(mut self, criterion: ExitCriterion) -> Self {
+        self.exit_criteria.push(criterion);
+        self
+    }
+
+    /// Fail the runs which do not send anything over RTT for `timeout_secs`
+    pub fn set_inactivity_timeout(mut self, timeout_secs: u32) -> Self {
+        self.inactivity_timeout_secs = Some(timeout_secs);
+        self
+    }
+
+    /// Run the firmware to `symbol` instead of `main` before starting the run
+    pub fn entry_symbol(mut self, symbol: impl Into<String>) -> Self {
+        self.entry_symbol = Some(symbol.into());
+        self
+    }
+
+    /// Start the run right from reset, without running to the entry symbol first
+    pub fn skip_run_to_entry(mut self) -> Self {
+        self.skip_run_to_entry = true;
+        self
+    }
+
+    /// Run without RTT, only the exit status and fault state of the firmware are captured
+    pub fn no_rtt(mut self) -> Self {
+        self.no_rtt = true;
+        self
+    }
+
+    /// Write the images straight into RAM and run them from there, without touching the flash
+    pub fn run_from_ram(mut self) -> Self {
+        self.execution_mode = ExecutionMode::Ram;
+        self
+    }
+
     /// Finish the task
     pub fn done(mut self) -> Result<JobDescBuilder> {
         if self.run_ons.len() == 0 {
             return Err(Error::NoRunOns);
         }
+        if self.images.is_empty() {
+            return Err(Error::NoImage);
+        }
         self.parent_builder.tasks.push(TaskDesc {
             run_on: self.run_ons,
-            binary_b64: base64::encode(self.elf.ok_or_else(|| Error::NoElf)?),
+            images: self.images,
+            rtt_channels: self.rtt_channels,
+            rtt_input: self.rtt_input,
+            exit_criteria: self.exit_criteria,
+            inactivity_timeout_secs: self.inactivity_timeout_secs,
+            entry_symbol: self.entry_symbol,
+            skip_run_to_entry: self.skip_run_to_entry,
+            no_rtt: self.no_rtt,
+            execution_mode: self.execution_mode,
         });
         Ok(self.parent_builder)
     }
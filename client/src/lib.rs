@@ -3,11 +3,13 @@
 //! Library providing the means of interfacing with the embedded CI server
 
 pub mod builder;
+mod sse;
 
 use std::time::Duration;
 
 use anyhow::anyhow;
 pub use embedded_ci_common::*;
+use futures_util::Stream;
 use reqwest::{StatusCode, Url};
 
 /// Possible errors produced by the [`Client`]
@@ -60,6 +62,8 @@ impl Client {
     }
 
     async fn post_job(&self, desc: job::JobDesc) -> Result<job::Job> {
+        // Reject the jobs the server would reject anyway, without uploading their binaries
+        desc.validate()?;
         let request_route = "/job";
         log::debug!("POST: {request_route}");
         let response = self
@@ -108,9 +112,61 @@ impl Client {
         result
     }
 
+    /// Cancel a job that is either queued or running
+    ///
+    /// A queued job is dropped and a running job is stopped. In both cases the
+    /// job's result becomes available with all of its runs marked as cancelled.
+    pub async fn cancel(&self, job_id: Uuid) -> Result<()> {
+        let request_route = format!("/job/by-id/{}", job_id);
+        log::debug!("DELETE: {request_route}");
+        let response = self
+            .request(reqwest::Method::DELETE, &request_route)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::ACCEPTED => Ok(()),
+            StatusCode::NOT_FOUND => Err(anyhow!("Job {job_id} not found"))?,
+            StatusCode::CONFLICT => Err(anyhow!("Job {job_id} has already finished"))?,
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized)?,
+            status_code => Err(anyhow!("Unexpected status code: {status_code}"))?,
+        }
+    }
+
     /// Run the job and wait for the result
     pub async fn run(&self, desc: job::JobDesc) -> Result<job::JobResult> {
         let job = self.post_job(desc).await?;
         self.poll_job_result(job).await
     }
+
+    /// Run the job and follow its progress live
+    ///
+    /// The stream yields the status transitions and the log lines of all the
+    /// runs as they are decoded, and ends with [`job::JobEvent::Finished`].
+    pub async fn run_streaming(
+        &self,
+        desc: job::JobDesc,
+    ) -> Result<impl Stream<Item = Result<job::JobEvent>>> {
+        let job = self.post_job(desc).await?;
+        let request_route = format!("/job/by-id/{}/stream", job.id);
+        log::debug!("GET: {request_route}");
+        let response = self
+            .request(reqwest::Method::GET, &request_route)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => Err(anyhow!(
+                "Job not found even though correctly enqueued, total server failure?"
+            ))?,
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized)?,
+            status_code => Err(anyhow!("Unexpected status code: {status_code}"))?,
+        }
+        Ok(futures_util::stream::unfold(
+            sse::EventReader::new(response),
+            |mut reader| async move {
+                let event = reader.next_event().await?;
+                Some((event, reader))
+            },
+        ))
+    }
 }
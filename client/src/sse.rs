@@ -0,0 +1,58 @@
+//! Minimal reader of the server-sent events emitted by the job stream
+
+use crate::{job, Error, Result};
+use anyhow::anyhow;
+
+/// Splits the response body into events and decodes the JSON carried in their `data` fields
+pub(crate) struct EventReader {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl EventReader {
+    pub(crate) fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Next event of the job, `None` once the job has finished or the stream has ended
+    pub(crate) async fn next_event(&mut self) -> Option<Result<job::JobEvent>> {
+        while !self.done {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+                let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                let data = String::from_utf8_lossy(&block)
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                // Comments, such as the server's heartbeats, carry no data
+                if data.is_empty() {
+                    continue;
+                }
+                let event = serde_json::from_str::<job::JobEvent>(&data)
+                    .map_err(|e| Error::Other(anyhow!("Malformed job event: {e}")));
+                self.done = matches!(event, Ok(job::JobEvent::Finished { .. }) | Err(_));
+                return Some(event);
+            }
+            match self.response.chunk().await {
+                Ok(Some(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+                Ok(None) => {
+                    self.done = true;
+                    return Some(Err(
+                        anyhow!("Job stream ended before the job finished").into()
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
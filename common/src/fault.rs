@@ -0,0 +1,242 @@
+//! Fault state of Cortex-M cores and its human readable reports.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Registers stacked by the hardware on exception entry
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExceptionFrame {
+    /// Stacked `R0`
+    pub r0: u32,
+    /// Stacked `R1`
+    pub r1: u32,
+    /// Stacked `R2`
+    pub r2: u32,
+    /// Stacked `R3`
+    pub r3: u32,
+    /// Stacked `R12`
+    pub r12: u32,
+    /// Stacked link register
+    pub lr: u32,
+    /// Stacked program counter, the instruction that faulted
+    pub pc: u32,
+    /// Stacked program status register
+    pub xpsr: u32,
+}
+
+/// Fault state read from a core, its contents depend on the architecture of the core
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultStatus {
+    /// ARMv6-M (Cortex-M0, M0+, M1) and ARMv8-M Baseline (Cortex-M23), which have no fault
+    /// status registers
+    Armv6m {
+        /// Exception frame stacked when entering the HardFault handler
+        stacked: Option<ExceptionFrame>,
+    },
+    /// ARMv7-M (Cortex-M3, M4, M7)
+    Armv7m {
+        /// HardFault Status Register
+        hfsr: u32,
+        /// Configurable Fault Status Register
+        cfsr: u32,
+        /// MemManage Fault Address Register, only read when marked as valid in the CFSR
+        mmfar: Option<u32>,
+        /// BusFault Address Register, only read when marked as valid in the CFSR
+        bfar: Option<u32>,
+    },
+    /// ARMv8-M Mainline (Cortex-M33, M55)
+    Armv8m {
+        /// HardFault Status Register
+        hfsr: u32,
+        /// Configurable Fault Status Register
+        cfsr: u32,
+        /// MemManage Fault Address Register, only read when marked as valid in the CFSR
+        mmfar: Option<u32>,
+        /// BusFault Address Register, only read when marked as valid in the CFSR
+        bfar: Option<u32>,
+        /// SecureFault Status Register, zero without the Security Extension
+        sfsr: u32,
+        /// SecureFault Address Register, only read when marked as valid in the SFSR
+        sfar: Option<u32>,
+    },
+    /// A core of an architecture whose fault state is not read, such as Cortex-A or RISC-V
+    Unsupported {
+        /// Name of the architecture
+        architecture: String,
+    },
+}
+
+const HFSR_BITS: &[(u32, &str)] = &[
+    (1, "VECTTBL: bus fault on a vector table read"),
+    (30, "FORCED: escalated from a configurable fault"),
+    (31, "DEBUGEVT: debug event"),
+];
+
+const CFSR_BITS: &[(u32, &str)] = &[
+    (0, "IACCVIOL: instruction access violation"),
+    (1, "DACCVIOL: data access violation"),
+    (3, "MUNSTKERR: MemManage fault on exception return"),
+    (4, "MSTKERR: MemManage fault on exception entry"),
+    (5, "MLSPERR: MemManage fault during lazy FP stacking"),
+    (8, "IBUSERR: instruction bus error"),
+    (9, "PRECISERR: precise data bus error"),
+    (10, "IMPRECISERR: imprecise data bus error"),
+    (11, "UNSTKERR: bus fault on exception return"),
+    (12, "STKERR: bus fault on exception entry"),
+    (13, "LSPERR: bus fault during lazy FP stacking"),
+    (16, "UNDEFINSTR: undefined instruction"),
+    (17, "INVSTATE: invalid EPSR state, e.g. Thumb bit cleared"),
+    (18, "INVPC: invalid EXC_RETURN value"),
+    (19, "NOCP: no coprocessor, e.g. the FPU is disabled"),
+    (20, "STKOF: stack limit overflow"),
+    (24, "UNALIGNED: unaligned access"),
+    (25, "DIVBYZERO: division by zero"),
+];
+
+const SFSR_BITS: &[(u32, &str)] = &[
+    (0, "INVEP: invalid Secure state entry point"),
+    (1, "INVIS: invalid integrity signature on exception return"),
+    (2, "INVER: invalid exception return"),
+    (3, "AUVIOL: attribution unit violation"),
+    (4, "INVTRAN: invalid Secure to Non-secure transition"),
+    (5, "LSPERR: SecureFault during lazy FP stacking"),
+    (7, "LSERR: lazy state error"),
+];
+
+/// Writes the name of the register followed by one line per set bit.
+fn write_bits(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    value: u32,
+    bits: &[(u32, &str)],
+) -> fmt::Result {
+    write!(f, "\n  {} = {:#010x}", name, value)?;
+    for (bit, description) in bits {
+        if value & (1 << bit) != 0 {
+            write!(f, "\n    {}", description)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_address(f: &mut fmt::Formatter<'_>, name: &str, value: Option<u32>) -> fmt::Result {
+    match value {
+        Some(value) => write!(f, "\n  {} = {:#010x}", name, value),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultStatus::Armv6m { stacked: None } => write!(f, "\n  Exception frame unavailable"),
+            FaultStatus::Armv6m {
+                stacked: Some(frame),
+            } => {
+                write!(f, "\n  Exception frame:")?;
+                write!(
+                    f,
+                    "\n    R0  = {:#010x}  R1 = {:#010x}  R2 = {:#010x}  R3 = {:#010x}",
+                    frame.r0, frame.r1, frame.r2, frame.r3
+                )?;
+                write!(
+                    f,
+                    "\n    R12 = {:#010x}  LR = {:#010x}  PC = {:#010x}  xPSR = {:#010x}",
+                    frame.r12, frame.lr, frame.pc, frame.xpsr
+                )
+            }
+            FaultStatus::Armv7m {
+                hfsr,
+                cfsr,
+                mmfar,
+                bfar,
+            } => {
+                write_bits(f, "HFSR", *hfsr, HFSR_BITS)?;
+                write_bits(f, "CFSR", *cfsr, CFSR_BITS)?;
+                write_address(f, "MMFAR", *mmfar)?;
+                write_address(f, "BFAR", *bfar)
+            }
+            FaultStatus::Armv8m {
+                hfsr,
+                cfsr,
+                mmfar,
+                bfar,
+                sfsr,
+                sfar,
+            } => {
+                write_bits(f, "HFSR", *hfsr, HFSR_BITS)?;
+                write_bits(f, "CFSR", *cfsr, CFSR_BITS)?;
+                write_address(f, "MMFAR", *mmfar)?;
+                write_address(f, "BFAR", *bfar)?;
+                if *sfsr != 0 {
+                    write_bits(f, "SFSR", *sfsr, SFSR_BITS)?;
+                }
+                write_address(f, "SFAR", *sfar)
+            }
+            FaultStatus::Unsupported { architecture } => {
+                write!(f, "\n  Fault state unavailable on {} cores", architecture)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn armv6m_reports_the_stacked_frame() {
+        let status = FaultStatus::Armv6m {
+            stacked: Some(ExceptionFrame {
+                pc: 0x0000_1234,
+                ..Default::default()
+            }),
+        };
+        let report = status.to_string();
+        assert!(report.contains("PC = 0x00001234"), "{}", report);
+        assert!(!report.contains("CFSR"), "{}", report);
+    }
+
+    #[test]
+    fn armv7m_breaks_down_the_set_bits() {
+        let status = FaultStatus::Armv7m {
+            hfsr: 1 << 30,
+            cfsr: 0x8200,
+            mmfar: None,
+            bfar: Some(0x2004_0000),
+        };
+        let report = status.to_string();
+        assert!(report.contains("FORCED"), "{}", report);
+        assert!(report.contains("PRECISERR"), "{}", report);
+        assert!(!report.contains("IMPRECISERR"), "{}", report);
+        assert!(report.contains("BFAR = 0x20040000"), "{}", report);
+    }
+
+    #[test]
+    fn armv8m_reports_secure_and_stack_limit_faults() {
+        let status = FaultStatus::Armv8m {
+            hfsr: 1 << 30,
+            cfsr: 1 << 20,
+            mmfar: None,
+            bfar: None,
+            sfsr: 0x50,
+            sfar: Some(0x1000_0000),
+        };
+        let report = status.to_string();
+        assert!(report.contains("STKOF"), "{}", report);
+        assert!(report.contains("INVTRAN"), "{}", report);
+        assert!(report.contains("SFAR = 0x10000000"), "{}", report);
+    }
+
+    #[test]
+    fn unsupported_architectures_are_named() {
+        let status = FaultStatus::Unsupported {
+            architecture: "Riscv".into(),
+        };
+        assert_eq!(
+            status.to_string(),
+            "\n  Fault state unavailable on Riscv cores"
+        );
+    }
+}
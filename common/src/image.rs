@@ -0,0 +1,361 @@
+//! Analysis of the images of tasks, shared by the server and the clients so that unusable
+//! images are rejected before they are queued.
+
+use crate::job::ImageFormat;
+use crate::{MemoryKind, MemoryRegion};
+use ihex::Record;
+use object::elf::{PT_LOAD, SHF_ALLOC};
+use object::read::elf::{ElfFile32, ProgramHeader};
+use object::{Endianness, File, Object, ObjectSection, ObjectSymbol, SectionFlags, SectionKind};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Bit set in the addresses of Thumb functions
+const THUMB_BIT: u32 = 1;
+
+/// Sections which have to be 4 byte aligned
+const IMPORTANT_SECTIONS: [&str; 4] = [".vector_table", ".text", ".rodata", ".data"];
+
+/// Problem found in an image
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The file could not be parsed
+    #[error("ELF parsing error, file is not an ELF file: '{0}'")]
+    Parse(String),
+    /// The Intel HEX file could not be parsed
+    #[error("Intel HEX parsing error: '{0}'")]
+    Ihex(String),
+    /// The image does not hold any data
+    #[error("The image is empty")]
+    Empty,
+    /// A section has no data
+    #[error("There is no data in section '{0}'")]
+    NoData(String),
+    /// A section is not 4 byte aligned
+    #[error("Section '{0}' is not 4 byte aligned")]
+    Unaligned(String),
+    /// A section is placed above the 32-bit address space
+    #[error("The address of section '{0}' is not 32-bit")]
+    Not32Bit(String),
+    /// The vector table is too small to hold the handlers used by the runner
+    #[error("Section '{0}' is too small, size = {1} bytes")]
+    VectorTableTooSmall(String, usize),
+    /// There is no vector table
+    #[error("'.vector_table' section not found")]
+    NoVectorTable,
+    /// A symbol is missing
+    #[error("'{0}' symbol not found")]
+    MissingSymbol(String),
+}
+
+/// Addresses found in the vector table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorTable {
+    /// Address of the table itself
+    pub start: u32,
+    /// Initial stack pointer
+    pub stack_pointer: u32,
+    /// Reset handler
+    pub reset: u32,
+    /// HardFault handler
+    pub hardfault: u32,
+}
+
+/// A section occupying memory of the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// Name of the section
+    pub name: String,
+    /// Addresses of the section while the firmware runs
+    pub range: Range<u64>,
+    /// Addresses the contents of the section are loaded to, `None` for sections without
+    /// contents such as `.bss`
+    pub load_range: Option<Range<u64>>,
+    /// Contents of the section, loaded to `load_range`
+    pub data: Vec<u8>,
+}
+
+/// What the runner needs to know about an image
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    /// The vector table of the firmware, `None` for the secondary images of a task, which are
+    /// only loaded next to the primary one
+    pub vector_table: Option<VectorTable>,
+    /// Sections occupying memory of the target
+    pub sections: Vec<Section>,
+    /// Symbols of the firmware, only ELF files have some
+    symbols: HashMap<String, u32>,
+}
+
+impl ImageInfo {
+    /// Parse the primary image of a task, stored in `format`, and check that the runner can start
+    /// it.
+    pub fn parse(image: &[u8], format: &ImageFormat) -> Result<Self, ImageError> {
+        Self::parse_as(image, format, true)
+    }
+
+    /// Parse a secondary image of a task, stored in `format`. Such images, like configuration
+    /// blobs, are only loaded so there is no vector table to find.
+    pub fn parse_secondary(image: &[u8], format: &ImageFormat) -> Result<Self, ImageError> {
+        Self::parse_as(image, format, false)
+    }
+
+    fn parse_as(image: &[u8], format: &ImageFormat, primary: bool) -> Result<Self, ImageError> {
+        match format {
+            ImageFormat::Elf => Self::parse_elf(image, primary),
+            ImageFormat::Ihex => Self::from_blocks(ihex_blocks(image)?, primary),
+            ImageFormat::Bin { base_address } => {
+                Self::from_blocks(vec![(*base_address, image.to_vec())], primary)
+            }
+        }
+    }
+
+    fn parse_elf(elf: &[u8], primary: bool) -> Result<Self, ImageError> {
+        let file = File::parse(elf).map_err(|e| ImageError::Parse(e.to_string()))?;
+
+        let vector_table = match primary {
+            true => Some(elf_vector_table(&file)?),
+            false => None,
+        };
+
+        let symbols = file
+            .symbols()
+            .filter_map(|symbol| Some((symbol.name().ok()?.to_string(), symbol.address() as u32)))
+            .collect();
+
+        Ok(Self {
+            vector_table,
+            sections: sections(&file, elf),
+            symbols,
+        })
+    }
+
+    /// Image made of raw blocks of data, as start address and contents.
+    ///
+    /// There is no section telling where the vector table is, it is expected at the start of the
+    /// lowest block.
+    fn from_blocks(mut blocks: Vec<(u64, Vec<u8>)>, primary: bool) -> Result<Self, ImageError> {
+        blocks.sort_by_key(|(address, _)| *address);
+        let sections: Vec<_> = blocks
+            .into_iter()
+            .map(|(address, data)| {
+                let range = address..address + data.len() as u64;
+                Section {
+                    name: format!("block at {:#010x}", address),
+                    range: range.clone(),
+                    load_range: Some(range),
+                    data,
+                }
+            })
+            .collect();
+
+        let first = sections.first().ok_or(ImageError::Empty)?;
+        let vector_table = match primary {
+            true => {
+                if first.range.start % 4 != 0 {
+                    return Err(ImageError::Unaligned(first.name.clone()));
+                }
+                Some(VectorTable::read(
+                    &first.name,
+                    first.range.start,
+                    &first.data,
+                )?)
+            }
+            false => None,
+        };
+
+        Ok(Self {
+            vector_table,
+            sections,
+            symbols: HashMap::new(),
+        })
+    }
+
+    /// Sections which do not fit in `memory_map`, with the addresses which do not fit.
+    ///
+    /// Each section has to fit in a single stretch of memory, both where it is loaded to and
+    /// where it runs from.
+    pub fn sections_outside(&self, memory_map: &[MemoryRegion]) -> Vec<(&Section, Range<u64>)> {
+        let memory = merge_regions(memory_map);
+        let fits = |range: &Range<u64>| {
+            memory
+                .iter()
+                .any(|region| region.start <= range.start && range.end <= region.end)
+        };
+        self.sections
+            .iter()
+            .flat_map(|section| {
+                section
+                    .load_range
+                    .iter()
+                    .chain([&section.range])
+                    .filter(|range| !fits(range))
+                    .map(move |range| (section, range.clone()))
+                    .take(1)
+            })
+            .collect()
+    }
+
+    /// Whether the vector table is in one of the RAM regions of `memory_map`, for firmwares linked
+    /// to run from RAM.
+    pub fn runs_from_ram(&self, memory_map: &[MemoryRegion]) -> bool {
+        self.vector_table.is_some_and(|vector_table| {
+            let start = u64::from(vector_table.start);
+            memory_map
+                .iter()
+                .any(|region| region.kind == MemoryKind::Ram && region.range.contains(&start))
+        })
+    }
+
+    /// Address of the data symbol `name`, if there is one.
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Address of the first instruction of the function `name`.
+    pub fn function(&self, name: &str) -> Result<u32, ImageError> {
+        self.symbol(name)
+            .map(|address| address & !THUMB_BIT)
+            .ok_or_else(|| ImageError::MissingSymbol(name.into()))
+    }
+}
+
+impl VectorTable {
+    /// Read the vector table from `data`, the contents of the section `name` at `address`.
+    fn read(name: &str, address: u64, data: &[u8]) -> Result<Self, ImageError> {
+        if data.len() < 16 {
+            return Err(ImageError::VectorTableTooSmall(name.into(), data.len()));
+        }
+
+        let vt: Vec<_> = data[..16]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        Ok(VectorTable {
+            start: address
+                .try_into()
+                .map_err(|_| ImageError::Not32Bit(name.into()))?,
+            stack_pointer: vt[0],
+            reset: vt[1],
+            hardfault: vt[3],
+        })
+    }
+}
+
+/// The vector table of an ELF file, checking that the sections the runner relies on are aligned.
+fn elf_vector_table(file: &File) -> Result<VectorTable, ImageError> {
+    let mut vector_table = None;
+    for section in file.sections() {
+        let name = match section.name() {
+            Ok(name) if IMPORTANT_SECTIONS.contains(&name) => name,
+            _ => continue,
+        };
+
+        let address = section.address();
+        if address % 4 != 0 {
+            // Can sections be unaligned?
+            return Err(ImageError::Unaligned(name.into()));
+        }
+
+        // If it is the vector table, get important addresses from it
+        if name == ".vector_table" {
+            let data = section
+                .data()
+                .map_err(|_| ImageError::NoData(name.into()))?;
+            vector_table = Some(VectorTable::read(name, address, data)?);
+        }
+    }
+    vector_table.ok_or(ImageError::NoVectorTable)
+}
+
+/// Blocks of contiguous data of an Intel HEX file, as start address and contents.
+fn ihex_blocks(hex: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, ImageError> {
+    let hex = std::str::from_utf8(hex).map_err(|e| ImageError::Ihex(e.to_string()))?;
+    let mut base_address = 0;
+    let mut blocks: Vec<(u64, Vec<u8>)> = Vec::new();
+    for record in ihex::Reader::new(hex) {
+        match record.map_err(|e| ImageError::Ihex(e.to_string()))? {
+            Record::Data { offset, value } => {
+                let address = base_address + offset as u64;
+                match blocks.last_mut() {
+                    Some((start, data)) if *start + data.len() as u64 == address => {
+                        data.extend(value)
+                    }
+                    _ => blocks.push((address, value)),
+                }
+            }
+            Record::ExtendedSegmentAddress(address) => base_address = (address as u64) * 16,
+            Record::ExtendedLinearAddress(address) => base_address = (address as u64) << 16,
+            _ => {}
+        }
+    }
+    Ok(blocks)
+}
+
+/// Sections of `file` occupying memory, `elf` is used to find where they are loaded to.
+fn sections(file: &File, elf: &[u8]) -> Vec<Section> {
+    // The generic API does not tell the load addresses, they come from the program headers
+    let load_segments: Vec<_> = ElfFile32::<Endianness>::parse(elf)
+        .map(|elf| {
+            let endian = elf.endian();
+            elf.raw_segments()
+                .iter()
+                .filter(|segment| segment.p_type(endian) == PT_LOAD)
+                .map(|segment| {
+                    let vaddr = segment.p_vaddr(endian) as u64;
+                    (
+                        vaddr..vaddr + segment.p_memsz(endian) as u64,
+                        segment.p_paddr(endian) as u64,
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    file.sections()
+        .filter(|section| {
+            matches!(section.flags(), SectionFlags::Elf { sh_flags } if sh_flags & SHF_ALLOC as u64 != 0)
+                && section.size() > 0
+        })
+        .map(|section| {
+            let range = section.address()..section.address() + section.size();
+            let load_range = match section.kind() {
+                SectionKind::UninitializedData | SectionKind::UninitializedTls => None,
+                _ => {
+                    let offset = load_segments
+                        .iter()
+                        .find(|(segment, _)| segment.contains(&range.start))
+                        .map_or(0, |(segment, paddr)| paddr.wrapping_sub(segment.start));
+                    Some(range.start.wrapping_add(offset)..range.end.wrapping_add(offset))
+                }
+            };
+            let data = match load_range {
+                Some(_) => section.data().unwrap_or_default().to_vec(),
+                None => Vec::new(),
+            };
+            Section {
+                name: section.name().unwrap_or_default().into(),
+                range,
+                load_range,
+                data,
+            }
+        })
+        .collect()
+}
+
+/// The address ranges of `memory_map`, with the adjacent regions merged.
+fn merge_regions(memory_map: &[MemoryRegion]) -> Vec<Range<u64>> {
+    let mut ranges: Vec<_> = memory_map
+        .iter()
+        .map(|region| region.range.clone())
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
@@ -1,8 +1,12 @@
 //! Module providing means of validation for job descriptors.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{ProbeSerial, RunOn, Target, Targets, UnordEqVec, Uuid};
+use crate::fault::FaultStatus;
+use crate::image::ImageInfo;
+use crate::{
+    JobStatus, MemoryKind, ProbeSerial, RunOn, Target, TargetName, Targets, UnordEqVec, Uuid,
+};
 use core::time::Duration;
 use serde::{Deserialize, Serialize};
 
@@ -34,6 +38,17 @@ impl Job {
             timeout: Duration::from_secs(desc.timeout_secs as _),
         })
     }
+
+    /// Probe serials of all the targets involved in this job
+    ///
+    /// A job can only be started once all of these are free
+    pub fn probe_serials(&self) -> HashSet<ProbeSerial> {
+        self.tasks
+            .iter()
+            .flat_map(|task| task.targets.iter())
+            .map(|target| target.probe_serial.clone())
+            .collect()
+    }
 }
 
 /// Task
@@ -49,19 +64,75 @@ pub struct Task {
     pub id: Uuid,
     /// Targets that should be involved as part of this task
     pub targets: Vec<Target>,
-    /// Deserialized ELF binary to be run on all the `targets`
-    #[serde(skip)]
-    pub binary: Vec<u8>,
+    /// Images flashed on all the `targets`, in order
+    pub images: Vec<Image>,
+    /// Index of the image in `images` whose symbols drive the run
+    #[serde(default)]
+    pub primary: usize,
+    /// RTT up channels to capture, all of them when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_channels: Option<Vec<RttChannel>>,
+    /// Input sent to the firmware over an RTT down channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_input: Option<RttInput>,
+    /// Conditions ending a run early, with whether the run passed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_criteria: Vec<ExitCriterion>,
+    /// Seconds without any RTT output after which a run is considered stalled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inactivity_timeout_secs: Option<u32>,
+    /// Symbol the firmware is run to before the run starts, `main` when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_symbol: Option<String>,
+    /// Start the run right from reset, without running to the entry symbol first
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_run_to_entry: bool,
+    /// Run without RTT, only the exit status and the fault state are captured
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_rtt: bool,
+    /// Where the images are written to and run from
+    #[serde(default, skip_serializing_if = "ExecutionMode::is_flash")]
+    pub execution_mode: ExecutionMode,
 }
 
 impl Task {
-    fn from_desc(targets: Vec<Target>, binary: Vec<u8>) -> Self {
+    fn from_desc(
+        targets: Vec<Target>,
+        images: Vec<Image>,
+        primary: usize,
+        desc: &TaskDesc,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             targets,
-            binary,
+            images,
+            primary,
+            rtt_channels: desc.rtt_channels.clone(),
+            rtt_input: desc.rtt_input.clone(),
+            exit_criteria: desc.exit_criteria.clone(),
+            inactivity_timeout_secs: desc.inactivity_timeout_secs,
+            entry_symbol: desc.entry_symbol.clone(),
+            skip_run_to_entry: desc.skip_run_to_entry,
+            no_rtt: desc.no_rtt,
+            execution_mode: desc.execution_mode,
         }
     }
+
+    /// The image whose symbols drive the run
+    pub fn primary_image(&self) -> &Image {
+        &self.images[self.primary]
+    }
+}
+
+/// An image of a [`Task`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    /// Deserialized image
+    #[serde(skip)]
+    pub binary: Vec<u8>,
+    /// Format of `binary`
+    #[serde(default, skip_serializing_if = "ImageFormat::is_elf")]
+    pub format: ImageFormat,
 }
 
 /// Result of a job
@@ -93,6 +164,8 @@ impl JobResult {
                 let run_result = RunResult {
                     target: target.clone(),
                     result: Default::default(),
+                    tests: Vec::new(),
+                    flash_skipped: false,
                 };
                 task_result.runs.push(run_result);
             }
@@ -139,6 +212,38 @@ pub struct RunResult {
     pub target: Target,
     /// Results of a run
     pub result: RunResultDetails,
+    /// Outcomes of the individual tests of a `defmt-test` firmware, empty for other firmwares
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestCase>,
+    /// Whether flashing was skipped as the target already held the images of the task
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub flash_skipped: bool,
+}
+
+/// Outcome of a single test of a `defmt-test` firmware
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TestCase {
+    /// Name of the test function
+    pub name: String,
+    /// Whether the test passed
+    pub status: TestStatus,
+    /// Time the test took to run, as seen by the server
+    pub duration_ms: u64,
+    /// Panic message of a failed test, if one was logged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Status of a single test of a `defmt-test` firmware
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TestStatus {
+    /// The test ran to completion
+    Passed,
+    /// The test panicked, or the run ended while it was running
+    Failed,
+    /// The test is marked with `#[ignore]`
+    Ignored,
 }
 
 /// Details of a given run
@@ -149,26 +254,309 @@ pub enum RunResultDetails {
     ///
     /// That means that the test firmware execution didn't reach the `BKPT` instruction before the [`Job::timeout`].
     Failure {
-        /// Stringified error returned by a runner
-        error: String,
+        /// Why the run has failed
+        reason: RunFailure,
+        /// `defmt` logs captured before the run failed
+        logs: Vec<LogRecord>,
     },
     /// Given run has succeeded
     ///
     /// That means that the test firmware execution reached the `BKPT` instruction before the [`Job::timeout`].
     Success {
         /// `defmt` logs captured as part of the run
-        logs: Vec<String>,
+        logs: Vec<LogRecord>,
+        /// Exit code signalled by the firmware, zero when known
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+    },
+    /// Given run has been cancelled
+    ///
+    /// That means that the job was cancelled either before the run started or while it was running.
+    Cancelled {
+        /// `defmt` logs captured before the run was stopped
+        logs: Vec<LogRecord>,
     },
 }
 
 impl Default for RunResultDetails {
     fn default() -> Self {
         Self::Failure {
-            error: "Never set, possibly timed out".into(),
+            reason: RunFailure::Other {
+                error: "Never set, possibly timed out".into(),
+            },
+            logs: Vec::new(),
+        }
+    }
+}
+
+/// Severity of a `defmt` log record
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    /// `defmt::trace!`
+    Trace,
+    /// `defmt::debug!`
+    Debug,
+    /// `defmt::info!`
+    Info,
+    /// `defmt::warn!`
+    Warn,
+    /// `defmt::error!`
+    Error,
+}
+
+impl LogLevel {
+    /// Upper case name of the level, as printed in front of the messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
         }
     }
 }
 
+/// A single log record captured from a target
+///
+/// Plain-text logs only fill in the [`LogRecord::message`], one record per line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Level of the record, `None` for `defmt::println!` and plain-text logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<LogLevel>,
+    /// Timestamp as formatted by the firmware's `defmt::timestamp!`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// The formatted message
+    pub message: String,
+    /// Source file the record was logged from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Line in [`LogRecord::file`] the record was logged from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    /// Module path the record was logged from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_path: Option<String>,
+    /// RTT up channel the record was read from, by name or by number when it has no name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+impl LogRecord {
+    /// Record holding a plain-text line
+    pub fn plain(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+        if let Some(level) = self.level {
+            write!(f, "{:<5} ", level.as_str())?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Reason of a failed run
+#[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunFailure {
+    /// The probe is not connected to the server
+    #[error("The probe was not found")]
+    ProbeNotFound,
+    /// Attaching to the target through the probe failed
+    #[error("Unable to attach to the target: {error}")]
+    AttachFailed {
+        /// Details of the problem
+        error: String,
+    },
+    /// One of the images is not usable by the runner, or lacks a symbol the task relies on
+    #[error("Invalid image: {error}")]
+    #[serde(alias = "invalid_elf")]
+    InvalidImage {
+        /// Details of the problem
+        error: String,
+    },
+    /// Flashing of the binary failed
+    #[error("Unable to flash the target: {error}")]
+    FlashFailed {
+        /// Details of the problem
+        error: String,
+    },
+    /// The firmware did not get to `main`
+    #[error("The app started, but did not reach to main: {error}")]
+    UnableToReachMain {
+        /// Details of the problem
+        error: String,
+    },
+    /// The RTT control block was not found in the target's memory
+    #[error("The RTT control block was not found")]
+    RttNotFound,
+    /// An RTT channel chosen for the task does not exist
+    #[error("The RTT channel {channel} was not found")]
+    RttChannelNotFound {
+        /// The channel as it was chosen, by number or by name
+        channel: String,
+    },
+    /// An exit criterion failing the run was met
+    #[error("Exit criterion met: {condition}")]
+    ExitCriterion {
+        /// The condition that was met
+        condition: ExitCondition,
+    },
+    /// The firmware did not halt before the timeout
+    #[error("The firmware reached timeout")]
+    Timeout,
+    /// The firmware did not send anything over RTT for too long
+    #[error(
+        "The firmware stalled, no RTT output for {}s{}",
+        silent_secs,
+        last_lines_report(last_lines)
+    )]
+    Stalled {
+        /// Time without any RTT output, in seconds
+        silent_secs: u32,
+        /// Last lines logged before the firmware went silent
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        last_lines: Vec<String>,
+    },
+    /// The core halted in the HardFault handler
+    #[error(
+        "Core halted for hardfault (LR = {:#010x}){}{}",
+        lr,
+        status,
+        backtrace_report(backtrace)
+    )]
+    HardFault {
+        /// Fault state of the core, depending on its architecture
+        status: FaultStatus,
+        /// Link register at the time of the fault
+        lr: u32,
+        /// Stack of the firmware at the time of the fault, innermost frame first
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        backtrace: Vec<BacktraceFrame>,
+    },
+    /// The core locked up
+    #[error("Core locked up")]
+    LockedUp,
+    /// The firmware exited with a non-zero exit code
+    #[error("Firmware exited with code {code}")]
+    ExitCode {
+        /// Exit code signalled through semihosting or a `bkpt` immediate
+        code: i32,
+    },
+    /// The firmware made a semihosting call other than an exit, which the runner does not service
+    #[error("Unsupported semihosting operation {operation:#04x}")]
+    Semihosting {
+        /// Number of the operation, taken from `r0`
+        operation: u32,
+    },
+    /// The core halted for any other reason
+    #[error("Core halted for unknown reason: {reason}")]
+    Halted {
+        /// Reason reported by the target
+        reason: String,
+    },
+    /// Any other error of the runner
+    #[error("{error}")]
+    Other {
+        /// Stringified error returned by a runner
+        error: String,
+    },
+}
+
+/// A frame of the backtrace of a faulted firmware
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// Program counter of the frame
+    pub address: u32,
+    /// Name of the function the frame is in, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    /// Source file of the frame, if the ELF has debug information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Line in [`BacktraceFrame::file`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+}
+
+impl std::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:#010x} @ {}",
+            self.address,
+            self.function.as_deref().unwrap_or("<unknown>")
+        )?;
+        if let Some(file) = &self.file {
+            write!(f, "\n      at {}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Numbered listing of the frames, empty if there are none.
+fn backtrace_report(backtrace: &[BacktraceFrame]) -> String {
+    let mut report = String::new();
+    if !backtrace.is_empty() {
+        report.push_str("\nBacktrace:");
+        for (index, frame) in backtrace.iter().enumerate() {
+            report.push_str(&format!("\n  {}: {}", index, frame));
+        }
+    }
+    report
+}
+
+fn last_lines_report(lines: &[String]) -> String {
+    let mut report = String::new();
+    if !lines.is_empty() {
+        report.push_str("\nLast lines:");
+        for line in lines {
+            report.push_str(&format!("\n  {}", line));
+        }
+    }
+    report
+}
+
+/// Progress of a job, streamed to the clients while the job is running
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobEvent {
+    /// Job has transitioned to a new status
+    Status {
+        /// Status of the job
+        status: JobStatus,
+    },
+    /// A log line has been decoded from one of the runs
+    Log {
+        /// Identifier matching the corresponding [`Task::id`]
+        task_id: Uuid,
+        /// Probe serial of the target the run is executing on
+        probe_serial: ProbeSerial,
+        /// Decoded log record
+        record: LogRecord,
+    },
+    /// Job has finished, this is the last event of the stream
+    Finished {
+        /// Result of the job
+        result: JobResult,
+    },
+}
+
 /// A job specification for a run.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JobDesc {
@@ -178,13 +566,235 @@ pub struct JobDesc {
     pub timeout_secs: u32,
 }
 
+impl JobDesc {
+    /// Validate the tasks on their own, without resolving their targets
+    ///
+    /// These are the checks of [`Job::from_desc`] which do not depend on the server, so that
+    /// clients can run them before submitting the job.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        for (index_t, task_desc) in self.tasks.iter().enumerate() {
+            validate_task(index_t, task_desc, &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 /// A task specification for a run. It is responsible for
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct TaskDesc {
     /// On which embedded targets should this task run on.
     pub run_on: Vec<RunOn>,
-    /// The ELF file holding the binary and debug symbols.
+    /// Images to flash in one session before the run, in order. Their data must not overlap.
+    ///
+    /// The symbols of the primary image are used to run the firmware to its entry and to find
+    /// RTT, a lone image is the primary one.
+    ///
+    /// The `binary_b64` of clients predating several images is accepted in its place, as a single
+    /// ELF file.
+    #[serde(alias = "binary_b64", deserialize_with = "images_or_elf")]
+    pub images: Vec<ImageDesc>,
+    /// RTT up channels to capture, all of them when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_channels: Option<Vec<RttChannel>>,
+    /// Input to send to the firmware over an RTT down channel while it runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_input: Option<RttInput>,
+    /// Conditions ending a run before the core halts, the first one met wins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_criteria: Vec<ExitCriterion>,
+    /// Fail a run once no RTT output arrived for this many seconds, on top of the job timeout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inactivity_timeout_secs: Option<u32>,
+    /// Symbol to run the firmware to before starting the run, `main` when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_symbol: Option<String>,
+    /// Start the run right from reset, for firmwares without an entry symbol to stop at.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_run_to_entry: bool,
+    /// Run without RTT, for firmwares which do not use it. Only the exit status and the fault
+    /// state of the firmware are captured.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_rtt: bool,
+    /// Where the images are written to and run from.
+    #[serde(default, skip_serializing_if = "ExecutionMode::is_flash")]
+    pub execution_mode: ExecutionMode,
+}
+
+/// Where the images of a task are written to and run from
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionMode {
+    /// The images are flashed, firmwares linked to run from RAM are still loaded into RAM
+    #[default]
+    Flash,
+    /// The images are written straight into RAM without touching the flash, and started from
+    /// their vector table. All of their sections must be linked to RAM.
+    Ram,
+}
+
+impl ExecutionMode {
+    /// Whether the images are flashed
+    pub fn is_flash(&self) -> bool {
+        *self == ExecutionMode::Flash
+    }
+}
+
+/// Deserialize [`TaskDesc::images`], either as a list or as the single ELF file of `binary_b64`
+fn images_or_elf<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ImageDesc>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Images {
+        List(Vec<ImageDesc>),
+        Elf(String),
+    }
+    Ok(match Images::deserialize(deserializer)? {
+        Images::List(images) => images,
+        Images::Elf(binary_b64) => vec![ImageDesc {
+            binary_b64,
+            format: ImageFormat::Elf,
+            primary: true,
+        }],
+    })
+}
+
+/// An image flashed by a task, such as a bootloader, an application or a configuration blob
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageDesc {
+    /// The image, an ELF file holding the binary and debug symbols unless `format` says
+    /// otherwise.
     pub binary_b64: String,
+    /// Format of the image.
+    #[serde(default, skip_serializing_if = "ImageFormat::is_elf")]
+    pub format: ImageFormat,
+    /// Whether the symbols of this image drive the run, see [`TaskDesc::images`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub primary: bool,
+}
+
+/// Format of the image of a task
+///
+/// Only ELF files carry symbols, tasks with images in other formats can neither be run to an
+/// entry symbol nor be stopped at a symbol, and their `defmt` logs cannot be decoded. RTT is
+/// looked up by scanning the RAM of the target.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// ELF file
+    #[default]
+    Elf,
+    /// Intel HEX file
+    Ihex,
+    /// Raw binary, loaded at `base_address`
+    Bin {
+        /// Address of the first byte of the binary
+        base_address: u64,
+    },
+}
+
+impl ImageFormat {
+    /// Whether the image is an ELF file, the only format carrying symbols
+    pub fn is_elf(&self) -> bool {
+        *self == ImageFormat::Elf
+    }
+}
+
+/// A condition ending a run, and whether the run passed when it is met
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExitCriterion {
+    /// The condition
+    pub when: ExitCondition,
+    /// Outcome of the run when the condition is met
+    pub outcome: ExitOutcome,
+}
+
+/// A condition ending a run, see [`ExitCriterion`]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitCondition {
+    /// A log line matches the regular expression
+    LogMatches(String),
+    /// A `defmt` record of at least this level is logged
+    LogLevel(LogLevel),
+    /// The firmware has been running for this many seconds
+    AfterSecs(u32),
+    /// The core reaches the function or address of the symbol
+    Symbol(String),
+}
+
+impl std::fmt::Display for ExitCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitCondition::LogMatches(pattern) => write!(f, "log matched `{}`", pattern),
+            ExitCondition::LogLevel(level) => write!(f, "{} record logged", level.as_str()),
+            ExitCondition::AfterSecs(secs) => write!(f, "ran for {}s", secs),
+            ExitCondition::Symbol(name) => write!(f, "reached `{}`", name),
+        }
+    }
+}
+
+/// Outcome of a run ended by an [`ExitCriterion`]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitOutcome {
+    /// The run succeeded
+    Pass,
+    /// The run failed
+    Fail,
+}
+
+/// Input sent to the firmware over an RTT down channel
+///
+/// The steps are executed in order while the runner polls RTT. Sending bytes right after the
+/// start is a script made of a single [`InputStep::Send`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RttInput {
+    /// Down channel to write to
+    #[serde(default = "default_down_channel")]
+    pub channel: RttChannel,
+    /// Steps of the script
+    pub steps: Vec<InputStep>,
+}
+
+fn default_down_channel() -> RttChannel {
+    RttChannel::Number(0)
+}
+
+/// A step of an [`RttInput`] script
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputStep {
+    /// Wait for a log line matching the regular expression, on any of the captured channels
+    Expect(String),
+    /// Send the text to the firmware
+    Send(String),
+    /// Send the base64 encoded bytes to the firmware, for commands that are not text
+    SendB64(String),
+}
+
+/// An RTT up channel, selected by its number or by the name the firmware gave it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RttChannel {
+    /// Number of the channel, `0` is the one `defmt-rtt` and `rtt-target` log to by default
+    Number(usize),
+    /// Name of the channel, such as `defmt`
+    Name(String),
+}
+
+impl std::fmt::Display for RttChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RttChannel::Number(number) => write!(f, "{}", number),
+            RttChannel::Name(name) => write!(f, "{}", name),
+        }
+    }
 }
 
 /// Error aggregating all found validation errors
@@ -249,6 +859,102 @@ pub enum ValidationError {
         /// Offending entry position in the JSON [`JobDesc`]
         entry: String,
     },
+    /// Given task does not define any images
+    #[error("Given task does not define any images: {entry}")]
+    NoImage {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+    },
+    /// A task with several images does not mark exactly one of them as primary
+    #[error("Exactly one of the images must be primary, {primaries} are for an entry: {entry}")]
+    PrimaryImageNotUnique {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Number of images marked as primary
+        primaries: usize,
+    },
+    /// A pattern of an input script or an exit criterion is not a valid regular expression
+    #[error("Invalid regular expression for an entry: {entry}: {error_details}")]
+    InvalidPattern {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Details of the problem
+        error_details: String,
+    },
+    /// The binary is not an image the runner can use
+    #[error("Invalid image for an entry: {entry}: {error_details}")]
+    InvalidImage {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Details of the problem
+        error_details: String,
+    },
+    /// The data of two images of a task overlap
+    #[error(
+        "The data of an entry overlaps the one of {other} ({start:#010x}..{end:#010x}): {entry}"
+    )]
+    ImagesOverlap {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Position of the image overlapped in the JSON [`JobDesc`]
+        other: String,
+        /// First overlapping address
+        start: u64,
+        /// End of the overlapping addresses
+        end: u64,
+    },
+    /// A symbol the task relies on is not in its ELF file
+    #[error("Symbol '{symbol}' not found in the ELF file for an entry: {entry}")]
+    MissingSymbol {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Name of the symbol
+        symbol: String,
+    },
+    /// A section of the ELF file does not fit in the memory of a target the task runs on
+    #[error(
+        "Section '{section}' ({start:#010x}..{end:#010x}) does not fit in the memory of {target_name} for an entry: {entry}"
+    )]
+    SectionOutOfTargetMemory {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Chip the section does not fit in
+        target_name: TargetName,
+        /// Name of the section
+        section: String,
+        /// First address of the section which does not fit
+        start: u64,
+        /// End of the addresses of the section which do not fit
+        end: u64,
+    },
+    /// A section of an image does not fit in the RAM of a target the task runs from RAM on
+    #[error(
+        "Section '{section}' ({start:#010x}..{end:#010x}) does not fit in the RAM of {target_name}, the task runs from RAM, for an entry: {entry}"
+    )]
+    SectionOutOfTargetRam {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+        /// Chip the section does not fit in
+        target_name: TargetName,
+        /// Name of the section
+        section: String,
+        /// First address of the section which does not fit
+        start: u64,
+        /// End of the addresses of the section which do not fit
+        end: u64,
+    },
+    /// An entry relies on symbols, which the image of its task does not have
+    #[error("An entry relies on symbols, which are only available in ELF files: {entry}")]
+    SymbolsUnavailable {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+    },
+    /// An entry relies on RTT, which is disabled for its task
+    #[error("An entry relies on RTT, which is disabled for its task: {entry}")]
+    RttDisabled {
+        /// Offending entry position in the JSON [`JobDesc`]
+        entry: String,
+    },
 }
 
 /// Validate tasks coherency, that is
@@ -339,13 +1045,17 @@ fn validate_tasks_coherency(
                 entry: format!("tasks.{}.run_on", index_t),
             });
         }
-        match base64::decode(&task_desc.binary_b64) {
-            Ok(binary) => tasks.push(Task::from_desc(targets, binary)),
-            Err(e) => errors.push(ValidationError::Base64DecodingFailed {
-                entry: format!("tasks.{}.binary_b64", index_t),
-                error_details: e.to_string(),
-            }),
-        };
+        if let Some((images, primary)) = validate_task(index_t, task_desc, &mut errors) {
+            check_memory_layout(
+                index_t,
+                &images,
+                primary,
+                task_desc.execution_mode,
+                &targets,
+                &mut errors,
+            );
+            tasks.push(Task::from_desc(targets, images, primary, task_desc));
+        }
     }
 
     position_target_map
@@ -383,10 +1093,302 @@ fn validate_tasks_coherency(
     }
 }
 
+/// Check that the images of a task fit in the memory of each of its targets, in their RAM when
+/// the task runs from RAM
+fn check_memory_layout(
+    index_t: usize,
+    images: &[Image],
+    primary: usize,
+    execution_mode: ExecutionMode,
+    targets: &[Target],
+    errors: &mut ValidationErrors,
+) {
+    for (index_i, image) in images.iter().enumerate() {
+        let image = match index_i == primary {
+            true => ImageInfo::parse(&image.binary, &image.format),
+            false => ImageInfo::parse_secondary(&image.binary, &image.format),
+        };
+        // Unusable images are reported by `validate_task`
+        let image = match image {
+            Ok(image) => image,
+            Err(_) => continue,
+        };
+        let mut checked = HashSet::new();
+        for target in targets {
+            if target.memory_map.is_empty() || !checked.insert(&target.target_name) {
+                continue;
+            }
+            let entry = format!("tasks.{}.images.{}.binary_b64", index_t, index_i);
+            let target_name = target.target_name.clone();
+            match execution_mode {
+                ExecutionMode::Flash => {
+                    for (section, range) in image.sections_outside(&target.memory_map) {
+                        errors.push(ValidationError::SectionOutOfTargetMemory {
+                            entry: entry.clone(),
+                            target_name: target_name.clone(),
+                            section: section.name.clone(),
+                            start: range.start,
+                            end: range.end,
+                        });
+                    }
+                }
+                ExecutionMode::Ram => {
+                    let ram: Vec<_> = target
+                        .memory_map
+                        .iter()
+                        .filter(|region| region.kind == MemoryKind::Ram)
+                        .cloned()
+                        .collect();
+                    for (section, range) in image.sections_outside(&ram) {
+                        errors.push(ValidationError::SectionOutOfTargetRam {
+                            entry: entry.clone(),
+                            target_name: target_name.clone(),
+                            section: section.name.clone(),
+                            start: range.start,
+                            end: range.end,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Index of the primary image of a task, a lone image is the primary one
+fn primary_image(
+    index_t: usize,
+    task_desc: &TaskDesc,
+    errors: &mut ValidationErrors,
+) -> Option<usize> {
+    let primaries: Vec<_> = task_desc
+        .images
+        .iter()
+        .enumerate()
+        .filter(|(_, image)| image.primary)
+        .map(|(index_i, _)| index_i)
+        .collect();
+    match (task_desc.images.len(), &primaries[..]) {
+        (0, _) => {
+            errors.push(ValidationError::NoImage {
+                entry: format!("tasks.{}.images", index_t),
+            });
+            None
+        }
+        (1, _) => Some(0),
+        (_, [primary]) => Some(*primary),
+        _ => {
+            errors.push(ValidationError::PrimaryImageNotUnique {
+                entry: format!("tasks.{}.images", index_t),
+                primaries: primaries.len(),
+            });
+            None
+        }
+    }
+}
+
+/// Validate the contents of a task on their own, returns its images and the index of the primary
+/// one when they could be decoded
+fn validate_task(
+    index_t: usize,
+    task_desc: &TaskDesc,
+    errors: &mut ValidationErrors,
+) -> Option<(Vec<Image>, usize)> {
+    for (index_s, step) in task_desc
+        .rtt_input
+        .iter()
+        .flat_map(|input| input.steps.iter())
+        .enumerate()
+    {
+        match step {
+            InputStep::Expect(pattern) => {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    errors.push(ValidationError::InvalidPattern {
+                        entry: format!("tasks.{}.rtt_input.steps.{}.expect", index_t, index_s),
+                        error_details: e.to_string(),
+                    });
+                }
+            }
+            InputStep::SendB64(data) => {
+                if let Err(e) = base64::decode(data) {
+                    errors.push(ValidationError::Base64DecodingFailed {
+                        entry: format!("tasks.{}.rtt_input.steps.{}.send_b64", index_t, index_s),
+                        error_details: e.to_string(),
+                    });
+                }
+            }
+            InputStep::Send(_) => {}
+        }
+    }
+    for (index_c, criterion) in task_desc.exit_criteria.iter().enumerate() {
+        if let ExitCondition::LogMatches(pattern) = &criterion.when {
+            if let Err(e) = regex::Regex::new(pattern) {
+                errors.push(ValidationError::InvalidPattern {
+                    entry: format!(
+                        "tasks.{}.exit_criteria.{}.when.log_matches",
+                        index_t, index_c
+                    ),
+                    error_details: e.to_string(),
+                });
+            }
+        }
+    }
+    if task_desc.no_rtt {
+        let rtt_disabled = |entry: &str| ValidationError::RttDisabled {
+            entry: format!("tasks.{}.{}", index_t, entry),
+        };
+        if task_desc.rtt_channels.is_some() {
+            errors.push(rtt_disabled("rtt_channels"));
+        }
+        if task_desc.rtt_input.is_some() {
+            errors.push(rtt_disabled("rtt_input"));
+        }
+        for (index_c, criterion) in task_desc.exit_criteria.iter().enumerate() {
+            if let ExitCondition::LogMatches(_) | ExitCondition::LogLevel(_) = criterion.when {
+                errors.push(rtt_disabled(&format!("exit_criteria.{}.when", index_c)));
+            }
+        }
+        if task_desc.inactivity_timeout_secs.is_some() {
+            errors.push(rtt_disabled("inactivity_timeout_secs"));
+        }
+    }
+    let primary = primary_image(index_t, task_desc, errors);
+    let mut images = Some(Vec::new());
+    let mut loaded = Vec::new();
+    for (index_i, image_desc) in task_desc.images.iter().enumerate() {
+        let entry = format!("tasks.{}.images.{}.binary_b64", index_t, index_i);
+        let binary = match base64::decode(&image_desc.binary_b64) {
+            Ok(binary) => binary,
+            Err(e) => {
+                errors.push(ValidationError::Base64DecodingFailed {
+                    entry,
+                    error_details: e.to_string(),
+                });
+                images = None;
+                continue;
+            }
+        };
+        // Only the primary image is started, the others merely have to be loadable
+        let image = match primary == Some(index_i) {
+            true => ImageInfo::parse(&binary, &image_desc.format),
+            false => ImageInfo::parse_secondary(&binary, &image_desc.format),
+        };
+        match image {
+            Ok(image) => {
+                if primary == Some(index_i) {
+                    check_symbols(index_t, task_desc, image_desc, &image, errors);
+                }
+                loaded.push((index_i, image));
+            }
+            Err(e) => errors.push(ValidationError::InvalidImage {
+                entry,
+                error_details: e.to_string(),
+            }),
+        }
+        if let Some(images) = &mut images {
+            images.push(Image {
+                binary,
+                format: image_desc.format.clone(),
+            });
+        }
+    }
+    check_overlaps(index_t, &loaded, errors);
+    Some((images?, primary?))
+}
+
+/// Check that the data of the `images` of a task, along with their indices, do not overlap, as
+/// the image flashed last would silently overwrite the others
+fn check_overlaps(index_t: usize, images: &[(usize, ImageInfo)], errors: &mut ValidationErrors) {
+    let load_ranges = |image: &ImageInfo| {
+        image
+            .sections
+            .iter()
+            .filter_map(|section| section.load_range.clone())
+            .collect::<Vec<_>>()
+    };
+    for (position, (index_a, image_a)) in images.iter().enumerate() {
+        let ranges_a = load_ranges(image_a);
+        for (index_b, image_b) in &images[position + 1..] {
+            let overlap = load_ranges(image_b).into_iter().find_map(|b| {
+                ranges_a
+                    .iter()
+                    .map(|a| a.start.max(b.start)..a.end.min(b.end))
+                    .find(|overlap| overlap.start < overlap.end)
+            });
+            if let Some(overlap) = overlap {
+                errors.push(ValidationError::ImagesOverlap {
+                    entry: format!("tasks.{}.images.{}.binary_b64", index_t, index_b),
+                    other: format!("tasks.{}.images.{}.binary_b64", index_t, index_a),
+                    start: overlap.start,
+                    end: overlap.end,
+                });
+            }
+        }
+    }
+}
+
+/// Check that the primary `image` of a task has the symbols the task relies on
+fn check_symbols(
+    index_t: usize,
+    task_desc: &TaskDesc,
+    image_desc: &ImageDesc,
+    image: &ImageInfo,
+    errors: &mut ValidationErrors,
+) {
+    if !image_desc.format.is_elf() {
+        let symbols_unavailable = |entry: String| ValidationError::SymbolsUnavailable {
+            entry: format!("tasks.{}.{}", index_t, entry),
+        };
+        if task_desc.entry_symbol.is_some() {
+            errors.push(symbols_unavailable("entry_symbol".into()));
+        }
+        for (index_c, criterion) in task_desc.exit_criteria.iter().enumerate() {
+            if let ExitCondition::Symbol(_) = criterion.when {
+                errors.push(symbols_unavailable(format!(
+                    "exit_criteria.{}.when.symbol",
+                    index_c
+                )));
+            }
+        }
+        return;
+    }
+
+    let mut require = |entry: String, symbol: &str| {
+        if image.function(symbol).is_err() {
+            errors.push(ValidationError::MissingSymbol {
+                entry,
+                symbol: symbol.into(),
+            });
+        }
+    };
+    if !task_desc.skip_run_to_entry {
+        require(
+            format!("tasks.{}.entry_symbol", index_t),
+            task_desc.entry_symbol.as_deref().unwrap_or("main"),
+        );
+    }
+    for (index_c, criterion) in task_desc.exit_criteria.iter().enumerate() {
+        if let ExitCondition::Symbol(symbol) = &criterion.when {
+            require(
+                format!("tasks.{}.exit_criteria.{}.when.symbol", index_t, index_c),
+                symbol,
+            );
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{ProbeAlias, ProbeSerial, TargetGroup, TargetName};
+    use crate::{MemoryKind, MemoryRegion, ProbeAlias, ProbeSerial, TargetGroup, TargetName};
+    use std::ops::Range;
+
+    fn image(binary_b64: String, format: ImageFormat) -> ImageDesc {
+        ImageDesc {
+            binary_b64,
+            format,
+            primary: false,
+        }
+    }
 
     fn get_available_targets() -> Vec<Target> {
         vec![
@@ -395,44 +1397,54 @@ mod tests {
                 probe_alias: ProbeAlias("PROBE_ALIAS_1".into()),
                 target_name: TargetName("TARGET_1".into()),
                 groups: vec![TargetGroup("GROUP_A".into())].into(),
+                memory_map: Vec::new(),
             },
             Target {
                 probe_serial: ProbeSerial("PROBE_SERIAL_2".into()),
                 probe_alias: ProbeAlias("PROBE_ALIAS_2".into()),
                 target_name: TargetName("TARGET_2".into()),
                 groups: vec![TargetGroup("GROUP_A".into())].into(),
+                memory_map: Vec::new(),
             },
             Target {
                 probe_serial: ProbeSerial("PROBE_SERIAL_3".into()),
                 probe_alias: ProbeAlias("PROBE_ALIAS_3".into()),
                 target_name: TargetName("TARGET_3".into()),
                 groups: vec![TargetGroup("GROUP_A".into())].into(),
+                memory_map: Vec::new(),
             },
             Target {
                 probe_serial: ProbeSerial("PROBE_SERIAL_4".into()),
                 probe_alias: ProbeAlias("PROBE_ALIAS_4".into()),
                 target_name: TargetName("TARGET_4".into()),
                 groups: vec![TargetGroup("GROUP_B".into())].into(),
+                memory_map: Vec::new(),
             },
             Target {
                 probe_serial: ProbeSerial("PROBE_SERIAL_5".into()),
                 probe_alias: ProbeAlias("PROBE_ALIAS_5".into()),
                 target_name: TargetName("TARGET_5".into()),
                 groups: vec![TargetGroup("GROUP_B".into())].into(),
+                memory_map: Vec::new(),
             },
         ]
     }
 
+    fn test_elf_b64() -> String {
+        base64::encode(crate::test_support::test_elf())
+    }
+
     #[test]
     fn valid_set_of_tasks() {
         let tasks = vec![TaskDesc {
-            binary_b64: "bm90X2NoZWNrZWQ=".into(),
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
             run_on: vec![
                 RunOn::ProbeSerials(vec![ProbeSerial("PROBE_SERIAL_1".into())]),
                 RunOn::ProbeAliases(vec![ProbeAlias("PROBE_ALIAS_2".into())]),
                 RunOn::Targets(vec![TargetName("TARGET_3".into())]),
                 RunOn::Groups(vec![TargetGroup("GROUP_B".into())]),
             ],
+            ..Default::default()
         }];
 
         let all_targets = get_available_targets();
@@ -448,21 +1460,23 @@ mod tests {
     fn invalid_b64_encoded_binary() {
         let tasks = vec![
             TaskDesc {
-                binary_b64: "c2hvdWxkX3dvcms=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
                     "PROBE_SERIAL_1".into(),
                 )])],
+                ..Default::default()
             },
             TaskDesc {
-                binary_b64: "ooops".into(),
+                images: vec![image("ooops".into(), ImageFormat::Elf)],
                 run_on: vec![RunOn::ProbeAliases(vec![ProbeAlias(
                     "PROBE_ALIAS_2".into(),
                 )])],
+                ..Default::default()
             },
         ];
         let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
         let expected = ValidationErrors::new(vec![ValidationError::Base64DecodingFailed {
-            entry: "tasks.1.binary_b64".into(),
+            entry: "tasks.1.images.0.binary_b64".into(),
             error_details: base64::DecodeError::InvalidLength.to_string(),
         }]);
         match result {
@@ -471,16 +1485,453 @@ mod tests {
         }
     }
 
+    #[test]
+    fn invalid_patterns() {
+        let tasks = vec![TaskDesc {
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
+            rtt_input: Some(RttInput {
+                channel: RttChannel::Number(0),
+                steps: vec![
+                    InputStep::Expect("^ready$".into()),
+                    InputStep::Send("go\n".into()),
+                    InputStep::Expect("(unclosed".into()),
+                    InputStep::SendB64("not base64".into()),
+                ],
+            }),
+            exit_criteria: vec![
+                ExitCriterion {
+                    when: ExitCondition::LogLevel(LogLevel::Error),
+                    outcome: ExitOutcome::Fail,
+                },
+                ExitCriterion {
+                    when: ExitCondition::LogMatches("[unclosed".into()),
+                    outcome: ExitOutcome::Pass,
+                },
+            ],
+            run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
+                "PROBE_SERIAL_1".into(),
+            )])],
+            ..Default::default()
+        }];
+        match validate_tasks_coherency(&tasks, &get_available_targets().into()) {
+            Err(result) => {
+                let entries: Vec<_> = result
+                    .errors
+                    .iter()
+                    .map(|error| match error {
+                        ValidationError::InvalidPattern { entry, .. }
+                        | ValidationError::Base64DecodingFailed { entry, .. } => entry.as_str(),
+                        error => panic!("unexpected error: {:?}", error),
+                    })
+                    .collect();
+                assert_eq!(
+                    entries,
+                    &[
+                        "tasks.0.rtt_input.steps.2.expect",
+                        "tasks.0.rtt_input.steps.3.send_b64",
+                        "tasks.0.exit_criteria.1.when.log_matches"
+                    ]
+                );
+            }
+            Ok(_) => panic!("expected invalid patterns"),
+        }
+    }
+
+    #[test]
+    fn rtt_options_without_rtt() {
+        let tasks = vec![TaskDesc {
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
+            rtt_channels: Some(vec![RttChannel::Number(0)]),
+            exit_criteria: vec![
+                ExitCriterion {
+                    when: ExitCondition::Symbol("TEST_DONE".into()),
+                    outcome: ExitOutcome::Pass,
+                },
+                ExitCriterion {
+                    when: ExitCondition::LogLevel(LogLevel::Error),
+                    outcome: ExitOutcome::Fail,
+                },
+            ],
+            inactivity_timeout_secs: Some(10),
+            skip_run_to_entry: true,
+            no_rtt: true,
+            run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
+                "PROBE_SERIAL_1".into(),
+            )])],
+            ..Default::default()
+        }];
+        let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
+        assert_eq!(
+            result.unwrap_err(),
+            ValidationErrors::new(vec![
+                ValidationError::RttDisabled {
+                    entry: "tasks.0.rtt_channels".into()
+                },
+                ValidationError::RttDisabled {
+                    entry: "tasks.0.exit_criteria.1.when".into()
+                },
+                ValidationError::RttDisabled {
+                    entry: "tasks.0.inactivity_timeout_secs".into()
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn unusable_elf_files() {
+        let task = |binary_b64: String, entry_symbol: Option<&str>, symbol: &str| TaskDesc {
+            images: vec![image(binary_b64, ImageFormat::Elf)],
+            exit_criteria: vec![ExitCriterion {
+                when: ExitCondition::Symbol(symbol.into()),
+                outcome: ExitOutcome::Pass,
+            }],
+            entry_symbol: entry_symbol.map(String::from),
+            run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
+                "PROBE_SERIAL_1".into(),
+            )])],
+            ..Default::default()
+        };
+        let desc = JobDesc {
+            tasks: vec![
+                task(test_elf_b64(), None, "TEST_DONE"),
+                task(base64::encode("not an ELF"), None, "TEST_DONE"),
+                task(test_elf_b64(), Some("start"), "DONE"),
+            ],
+            timeout_secs: 10,
+        };
+        let expected = ValidationErrors::new(vec![
+            ValidationError::InvalidImage {
+                entry: "tasks.1.images.0.binary_b64".into(),
+                error_details:
+                    "ELF parsing error, file is not an ELF file: 'Could not read file magic'".into(),
+            },
+            ValidationError::MissingSymbol {
+                entry: "tasks.2.entry_symbol".into(),
+                symbol: "start".into(),
+            },
+            ValidationError::MissingSymbol {
+                entry: "tasks.2.exit_criteria.0.when.symbol".into(),
+                symbol: "DONE".into(),
+            },
+        ]);
+        assert_eq!(desc.validate().unwrap_err(), expected);
+    }
+
+    #[test]
+    fn sections_must_fit_in_target_memory() {
+        let target = |probe_serial: &str, target_name: &str, flash: Range<u64>| Target {
+            probe_serial: ProbeSerial(probe_serial.into()),
+            probe_alias: Default::default(),
+            target_name: TargetName(target_name.into()),
+            groups: vec![TargetGroup("GROUP_A".into())].into(),
+            memory_map: vec![
+                MemoryRegion {
+                    kind: MemoryKind::Flash,
+                    range: flash,
+                },
+                MemoryRegion {
+                    kind: MemoryKind::Ram,
+                    range: 0x2000_0000..0x2001_0000,
+                },
+            ],
+        };
+        let targets = vec![
+            target("PROBE_SERIAL_1", "SMALL_AT_0", 0..0x1000),
+            target("PROBE_SERIAL_2", "AT_0x0800", 0x0800_0000..0x0810_0000),
+            target("PROBE_SERIAL_3", "AT_0x0800", 0x0800_0000..0x0810_0000),
+        ];
+        let tasks = vec![TaskDesc {
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        }];
+        let section = |section: &str, end| ValidationError::SectionOutOfTargetMemory {
+            entry: "tasks.0.images.0.binary_b64".into(),
+            target_name: TargetName("AT_0x0800".into()),
+            section: section.into(),
+            start: 0,
+            end,
+        };
+        assert_eq!(
+            validate_tasks_coherency(&tasks, &targets.into()).unwrap_err(),
+            ValidationErrors::new(vec![
+                section(".vector_table", 16),
+                section(".text", 12),
+                section(".data", 48)
+            ])
+        );
+    }
+
+    /// Intel HEX file holding `data` at 0x0800_0000, split in records of 16 bytes
+    fn test_ihex_b64(data: &[u8]) -> String {
+        let mut records = vec![ihex::Record::ExtendedLinearAddress(0x0800)];
+        for (index, chunk) in data.chunks(16).enumerate() {
+            records.push(ihex::Record::Data {
+                offset: (index * 16) as u16,
+                value: chunk.to_vec(),
+            });
+        }
+        records.push(ihex::Record::EndOfFile);
+        base64::encode(ihex::create_object_file_representation(&records).unwrap())
+    }
+
+    #[test]
+    fn several_images() {
+        let task = |images: Vec<ImageDesc>, symbol: Option<&str>| TaskDesc {
+            images,
+            exit_criteria: symbol
+                .map(|symbol| ExitCriterion {
+                    when: ExitCondition::Symbol(symbol.into()),
+                    outcome: ExitOutcome::Pass,
+                })
+                .into_iter()
+                .collect(),
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        };
+        let elf = || image(test_elf_b64(), ImageFormat::Elf);
+        let bin = || {
+            image(
+                base64::encode([0; 32]),
+                ImageFormat::Bin {
+                    base_address: 0x0800_0000,
+                },
+            )
+        };
+        let primary = |image: ImageDesc| ImageDesc {
+            primary: true,
+            ..image
+        };
+        let desc = JobDesc {
+            tasks: vec![
+                task(vec![bin(), primary(elf())], Some("TEST_DONE")),
+                task(vec![bin(), elf()], None),
+                task(Vec::new(), None),
+                task(vec![primary(elf()), primary(elf())], None),
+                task(vec![elf(), primary(bin())], Some("TEST_DONE")),
+                task(
+                    vec![primary(elf()), image("ooops".into(), ImageFormat::Ihex)],
+                    None,
+                ),
+            ],
+            timeout_secs: 10,
+        };
+        let expected = ValidationErrors::new(vec![
+            ValidationError::PrimaryImageNotUnique {
+                entry: "tasks.1.images".into(),
+                primaries: 0,
+            },
+            ValidationError::NoImage {
+                entry: "tasks.2.images".into(),
+            },
+            ValidationError::PrimaryImageNotUnique {
+                entry: "tasks.3.images".into(),
+                primaries: 2,
+            },
+            ValidationError::ImagesOverlap {
+                entry: "tasks.3.images.1.binary_b64".into(),
+                other: "tasks.3.images.0.binary_b64".into(),
+                start: 0,
+                end: 16,
+            },
+            ValidationError::SymbolsUnavailable {
+                entry: "tasks.4.exit_criteria.0.when.symbol".into(),
+            },
+            ValidationError::Base64DecodingFailed {
+                entry: "tasks.5.images.1.binary_b64".into(),
+                error_details: "Encoded text cannot have a 6-bit remainder.".into(),
+            },
+        ]);
+        assert_eq!(desc.validate().unwrap_err(), expected);
+    }
+
+    #[test]
+    fn images_must_not_overlap() {
+        let blob = |base_address: u64, size: usize| {
+            image(
+                base64::encode(vec![0; size]),
+                ImageFormat::Bin { base_address },
+            )
+        };
+        let task = |images: Vec<ImageDesc>| TaskDesc {
+            images: images
+                .into_iter()
+                .enumerate()
+                .map(|(index, image)| ImageDesc {
+                    primary: index == 0,
+                    ..image
+                })
+                .collect(),
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        };
+        let desc = JobDesc {
+            tasks: vec![
+                // Adjacent images are fine
+                task(vec![blob(0x0800_0000, 32), blob(0x0800_0020, 8)]),
+                task(vec![
+                    blob(0x0800_0000, 32),
+                    blob(0x0800_1000, 8),
+                    blob(0x0800_0010, 8),
+                ]),
+            ],
+            timeout_secs: 10,
+        };
+        let expected = ValidationErrors::new(vec![ValidationError::ImagesOverlap {
+            entry: "tasks.1.images.2.binary_b64".into(),
+            other: "tasks.1.images.0.binary_b64".into(),
+            start: 0x0800_0010,
+            end: 0x0800_0018,
+        }]);
+        assert_eq!(desc.validate().unwrap_err(), expected);
+    }
+
+    #[test]
+    fn images_without_symbols() {
+        let task = |binary_b64: String, format: ImageFormat, symbols: bool| TaskDesc {
+            images: vec![image(binary_b64, format)],
+            exit_criteria: if symbols {
+                vec![ExitCriterion {
+                    when: ExitCondition::Symbol("TEST_DONE".into()),
+                    outcome: ExitOutcome::Pass,
+                }]
+            } else {
+                Vec::new()
+            },
+            entry_symbol: symbols.then(|| "main".into()),
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        };
+        let bin = ImageFormat::Bin {
+            base_address: 0x0800_0000,
+        };
+        let desc = JobDesc {
+            tasks: vec![
+                task(base64::encode([0; 32]), bin.clone(), false),
+                task(test_ihex_b64(&[0; 40]), ImageFormat::Ihex, false),
+                task(test_ihex_b64(&[0; 40]), ImageFormat::Ihex, true),
+                task(base64::encode([0; 8]), bin.clone(), false),
+                // Secondary images, such as configuration blobs, have no vector table
+                TaskDesc {
+                    images: vec![
+                        ImageDesc {
+                            primary: true,
+                            ..image(base64::encode([0; 32]), bin.clone())
+                        },
+                        image(
+                            base64::encode([0; 8]),
+                            ImageFormat::Bin {
+                                base_address: 0x0800_1000,
+                            },
+                        ),
+                    ],
+                    ..task(String::new(), bin, false)
+                },
+            ],
+            timeout_secs: 10,
+        };
+        let expected = ValidationErrors::new(vec![
+            ValidationError::SymbolsUnavailable {
+                entry: "tasks.2.entry_symbol".into(),
+            },
+            ValidationError::SymbolsUnavailable {
+                entry: "tasks.2.exit_criteria.0.when.symbol".into(),
+            },
+            ValidationError::InvalidImage {
+                entry: "tasks.3.images.0.binary_b64".into(),
+                error_details: "Section 'block at 0x08000000' is too small, size = 8 bytes".into(),
+            },
+        ]);
+        assert_eq!(desc.validate().unwrap_err(), expected);
+    }
+
+    #[test]
+    fn image_blocks_must_fit_in_target_memory() {
+        let targets = vec![Target {
+            probe_serial: ProbeSerial("PROBE_SERIAL_1".into()),
+            probe_alias: Default::default(),
+            target_name: TargetName("AT_0x0800".into()),
+            groups: vec![TargetGroup("GROUP_A".into())].into(),
+            memory_map: vec![MemoryRegion {
+                kind: MemoryKind::Flash,
+                range: 0x0800_0000..0x0800_0020,
+            }],
+        }];
+        let tasks = vec![TaskDesc {
+            images: vec![image(test_ihex_b64(&[0; 40]), ImageFormat::Ihex)],
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        }];
+        assert_eq!(
+            validate_tasks_coherency(&tasks, &targets.into()).unwrap_err(),
+            ValidationErrors::new(vec![ValidationError::SectionOutOfTargetMemory {
+                entry: "tasks.0.images.0.binary_b64".into(),
+                target_name: TargetName("AT_0x0800".into()),
+                section: "block at 0x08000000".into(),
+                start: 0x0800_0000,
+                end: 0x0800_0028,
+            }])
+        );
+    }
+
+    #[test]
+    fn ram_execution_mode() {
+        let memory_map = vec![
+            MemoryRegion {
+                kind: MemoryKind::Flash,
+                range: 0x0800_0000..0x0810_0000,
+            },
+            MemoryRegion {
+                kind: MemoryKind::Ram,
+                range: 0x2000_0000..0x2001_0000,
+            },
+        ];
+        let targets = vec![Target {
+            probe_serial: ProbeSerial("PROBE_SERIAL_1".into()),
+            probe_alias: Default::default(),
+            target_name: TargetName("AT_0x0800".into()),
+            groups: vec![TargetGroup("GROUP_A".into())].into(),
+            memory_map: memory_map.clone(),
+        }];
+        let bin = |base_address| ImageFormat::Bin { base_address };
+        let task = |base_address: u64| TaskDesc {
+            images: vec![image(base64::encode([0; 32]), bin(base_address))],
+            execution_mode: ExecutionMode::Ram,
+            run_on: vec![RunOn::Groups(vec![TargetGroup("GROUP_A".into())])],
+            ..Default::default()
+        };
+        assert!(validate_tasks_coherency([task(0x2000_0000)], &targets.clone().into()).is_ok());
+        assert_eq!(
+            validate_tasks_coherency([task(0x0800_0000)], &targets.into()).unwrap_err(),
+            ValidationErrors::new(vec![ValidationError::SectionOutOfTargetRam {
+                entry: "tasks.0.images.0.binary_b64".into(),
+                target_name: TargetName("AT_0x0800".into()),
+                section: "block at 0x08000000".into(),
+                start: 0x0800_0000,
+                end: 0x0800_0020,
+            }])
+        );
+
+        let runs_from_ram = |base_address| {
+            ImageInfo::parse(&[0; 32], &bin(base_address))
+                .unwrap()
+                .runs_from_ram(&memory_map)
+        };
+        assert!(runs_from_ram(0x2000_0000));
+        assert!(!runs_from_ram(0x0800_0000));
+    }
+
     #[test]
     fn target_duplicated_within_task() {
         let tasks = vec![TaskDesc {
-            binary_b64: "bm90X2NoZWNrZWQ=".into(),
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
             run_on: vec![
                 RunOn::ProbeSerials(vec![ProbeSerial("PROBE_SERIAL_2".into())]),
                 RunOn::ProbeAliases(vec![ProbeAlias("PROBE_ALIAS_2".into())]),
                 RunOn::Targets(vec![TargetName("TARGET_2".into())]),
                 RunOn::Groups(vec![TargetGroup("GROUP_B".into())]),
             ],
+            ..Default::default()
         }];
         let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
         let expected = ValidationErrors::new(vec![ValidationError::TargetIsNotUnique {
@@ -489,6 +1940,7 @@ mod tests {
                 probe_alias: ProbeAlias("PROBE_ALIAS_2".into()),
                 target_name: TargetName("TARGET_2".into()),
                 groups: vec![TargetGroup("GROUP_A".into())].into(),
+                memory_map: Vec::new(),
             },
             entries: vec![
                 "TARGET_2 @ tasks.0.run_on.2.targets.0".into(),
@@ -509,20 +1961,23 @@ mod tests {
     fn target_duplicated_between_tasks() {
         let tasks = vec![
             TaskDesc {
-                binary_b64: "bm90X2NoZWNrZWQ=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
                     "PROBE_SERIAL_2".into(),
                 )])],
+                ..Default::default()
             },
             TaskDesc {
-                binary_b64: "bm90X2NoZWNrZWQ=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![RunOn::ProbeAliases(vec![ProbeAlias(
                     "PROBE_ALIAS_2".into(),
                 )])],
+                ..Default::default()
             },
             TaskDesc {
-                binary_b64: "bm90X2NoZWNrZWQ=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![RunOn::Targets(vec![TargetName("TARGET_2".into())])],
+                ..Default::default()
             },
         ];
         let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
@@ -532,6 +1987,7 @@ mod tests {
                 probe_alias: ProbeAlias("PROBE_ALIAS_2".into()),
                 target_name: TargetName("TARGET_2".into()),
                 groups: vec![TargetGroup("GROUP_A".into())].into(),
+                memory_map: Vec::new(),
             },
             entries: vec![
                 "TARGET_2 @ tasks.2.run_on.0.targets.0".into(),
@@ -549,13 +2005,14 @@ mod tests {
     #[test]
     fn target_duplicated_via_group() {
         let tasks = vec![TaskDesc {
-            binary_b64: "bm90X2NoZWNrZWQ=".into(),
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
             run_on: vec![
                 RunOn::ProbeSerials(vec![ProbeSerial("PROBE_SERIAL_1".into())]),
                 RunOn::ProbeAliases(vec![ProbeAlias("PROBE_ALIAS_2".into())]),
                 RunOn::Targets(vec![TargetName("TARGET_3".into())]),
                 RunOn::Groups(vec![TargetGroup("GROUP_A".into())]),
             ],
+            ..Default::default()
         }];
 
         let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
@@ -566,6 +2023,7 @@ mod tests {
                     probe_alias: ProbeAlias("PROBE_ALIAS_1".into()),
                     target_name: TargetName("TARGET_1".into()),
                     groups: vec![TargetGroup("GROUP_A".into())].into(),
+                    memory_map: Vec::new(),
                 },
                 entries: vec![
                     "PROBE_SERIAL_1 @ tasks.0.run_on.0.probe_serials.0".into(),
@@ -579,6 +2037,7 @@ mod tests {
                     probe_alias: ProbeAlias("PROBE_ALIAS_2".into()),
                     target_name: TargetName("TARGET_2".into()),
                     groups: vec![TargetGroup("GROUP_A".into())].into(),
+                    memory_map: Vec::new(),
                 },
                 entries: vec![
                     "PROBE_ALIAS_2 @ tasks.0.run_on.1.probe_aliases.0".into(),
@@ -592,6 +2051,7 @@ mod tests {
                     probe_alias: ProbeAlias("PROBE_ALIAS_3".into()),
                     target_name: TargetName("TARGET_3".into()),
                     groups: vec![TargetGroup("GROUP_A".into())].into(),
+                    memory_map: Vec::new(),
                 },
                 entries: vec![
                     "TARGET_3 @ tasks.0.run_on.2.targets.0".into(),
@@ -609,13 +2069,14 @@ mod tests {
     #[test]
     fn target_does_not_exist() {
         let tasks = vec![TaskDesc {
-            binary_b64: "bm90X2NoZWNrZWQ=".into(),
+            images: vec![image(test_elf_b64(), ImageFormat::Elf)],
             run_on: vec![
                 RunOn::ProbeSerials(vec![ProbeSerial("PROBE_SERIAL_1".into())]),
                 RunOn::ProbeAliases(vec![ProbeAlias("PROBE_ALIAS_2".into())]),
                 RunOn::Targets(vec![TargetName("TARGET_3".into())]),
                 RunOn::Groups(vec![TargetGroup("GROUP_C".into())]),
             ],
+            ..Default::default()
         }];
 
         let result = validate_tasks_coherency(&tasks, &get_available_targets().into());
@@ -632,14 +2093,16 @@ mod tests {
     fn target_not_specified() {
         let tasks = vec![
             TaskDesc {
-                binary_b64: "bm90X2NoZWNrZWQ=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![RunOn::ProbeSerials(vec![ProbeSerial(
                     "PROBE_SERIAL_1".into(),
                 )])],
+                ..Default::default()
             },
             TaskDesc {
-                binary_b64: "bm90X2NoZWNrZWQ=".into(),
+                images: vec![image(test_elf_b64(), ImageFormat::Elf)],
                 run_on: vec![],
+                ..Default::default()
             },
         ];
 
@@ -652,4 +2115,85 @@ mod tests {
             Err(result) => assert_eq!(result, expected),
         }
     }
+
+    #[test]
+    fn rtt_channels_are_selected_by_number_or_name() {
+        let task: TaskDesc = serde_json::from_str(
+            r#"{"run_on": [], "images": [], "rtt_channels": [0, "telemetry"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            task.rtt_channels,
+            Some(vec![
+                RttChannel::Number(0),
+                RttChannel::Name("telemetry".into())
+            ])
+        );
+    }
+
+    #[test]
+    fn binary_b64_is_a_single_elf_file() {
+        let task: TaskDesc =
+            serde_json::from_str(r#"{"run_on": [], "binary_b64": "AAAA"}"#).unwrap();
+        match &task.images[..] {
+            [ImageDesc {
+                binary_b64,
+                format: ImageFormat::Elf,
+                primary: true,
+            }] => assert_eq!(binary_b64, "AAAA"),
+            v => panic!("unexpected images: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn failure_kind_is_serialized_apart_from_the_logs() {
+        let details = RunResultDetails::Failure {
+            reason: RunFailure::HardFault {
+                status: FaultStatus::Armv7m {
+                    hfsr: 1 << 30,
+                    cfsr: 0x8200,
+                    mmfar: None,
+                    bfar: Some(0x2004_0000),
+                },
+                lr: 0xffff_fff9,
+                backtrace: Vec::new(),
+            },
+            logs: vec![LogRecord::plain("before")],
+        };
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "failure": {
+                    "reason": {
+                        "hard_fault": {
+                            "status": {
+                                "armv7m": {
+                                    "hfsr": 0x4000_0000,
+                                    "cfsr": 0x8200,
+                                    "mmfar": null,
+                                    "bfar": 0x2004_0000,
+                                }
+                            },
+                            "lr": 0xffff_fff9_u32,
+                        }
+                    },
+                    "logs": [{ "message": "before" }],
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_elf_failures_are_still_read() {
+        let reason: RunFailure =
+            serde_json::from_str(r#"{"invalid_elf": {"error": "'main' symbol not found"}}"#)
+                .unwrap();
+        assert_eq!(
+            reason,
+            RunFailure::InvalidImage {
+                error: "'main' symbol not found".into()
+            }
+        );
+    }
 }
@@ -5,18 +5,25 @@
 
 pub use uuid::Uuid;
 
+pub mod fault;
+pub mod image;
 pub mod job;
+pub mod report;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod test_support;
 
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashSet, VecDeque},
     hash::Hash,
+    ops::Range,
 };
 
 /// Current status of the server
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ServerStatus {
-    current_job: Option<Uuid>,
+    running_jobs: HashSet<Uuid>,
     jobs_in_queue: VecDeque<Uuid>,
     jobs_finished: HashSet<Uuid>,
 }
@@ -34,17 +41,6 @@ pub enum JobStatus {
     Finished,
 }
 
-macro_rules! error_if_not_eq {
-    ($l:expr, $r:expr) => {{
-        if $l != $r {
-            log::error!(
-                "assertions failed: {} != {}",
-                stringify!($l),
-                stringify!($r)
-            )
-        }
-    }};
-}
 macro_rules! error_if_eq {
     ($l:expr, $r:expr) => {{
         if $l == $r {
@@ -67,17 +63,32 @@ macro_rules! error_if_not {
 impl ServerStatus {
     /// Marks the enqueued job as started
     ///
-    /// Assumes the oldest `id` in the queue to be `id`
+    /// Jobs do not have to be started in the order they were enqueued in, as
+    /// jobs on disjoint sets of targets can overtake each other
     pub fn job_started(&mut self, id: Uuid) {
-        let oldest_job = self.jobs_in_queue.pop_front().unwrap();
-        error_if_not_eq!(oldest_job, id);
-        error_if_not_eq!(self.current_job.replace(id), None);
+        let position = self.jobs_in_queue.iter().position(|&j| j == id);
+        error_if_eq!(position, None);
+        if let Some(position) = position {
+            self.jobs_in_queue.remove(position);
+        }
+        error_if_not!(self.running_jobs.insert(id));
     }
     /// Marks the running job as finished
     ///
     /// Assumes `job_started` called with the same `id`
     pub fn job_finished(&mut self, id: Uuid) {
-        error_if_not_eq!(self.current_job.take(), Some(id));
+        error_if_not!(self.running_jobs.remove(&id));
+        error_if_not!(self.jobs_finished.insert(id));
+    }
+    /// Marks the enqueued job as finished without it ever being started
+    ///
+    /// Assumes `id` to be enqueued
+    pub fn job_cancelled(&mut self, id: Uuid) {
+        let position = self.jobs_in_queue.iter().position(|&j| j == id);
+        error_if_eq!(position, None);
+        if let Some(position) = position {
+            self.jobs_in_queue.remove(position);
+        }
         error_if_not!(self.jobs_finished.insert(id));
     }
     /// Removes a finished job
@@ -90,14 +101,23 @@ impl ServerStatus {
     ///
     /// Assumes `id` is not currently enqueued, running nor finished (must be cleared)
     pub fn job_enqueued(&mut self, id: Uuid) {
-        error_if_eq!(self.current_job, Some(id));
+        error_if_not!(!self.running_jobs.contains(&id));
         error_if_not!(!self.jobs_in_queue.contains(&id));
         error_if_not!(!self.jobs_finished.contains(&id));
         self.jobs_in_queue.push_back(id);
     }
+    /// Creates a job entry and marks it as finished, for a job whose result was restored after a
+    /// restart
+    ///
+    /// Assumes `id` is not currently enqueued, running nor finished (must be cleared)
+    pub fn job_restored(&mut self, id: Uuid) {
+        error_if_not!(!self.running_jobs.contains(&id));
+        error_if_not!(!self.jobs_in_queue.contains(&id));
+        error_if_not!(self.jobs_finished.insert(id));
+    }
     /// Job status getter
     pub fn job_status(&self, id: Uuid) -> JobStatus {
-        if self.current_job == Some(id) {
+        if self.running_jobs.contains(&id) {
             return JobStatus::Running;
         }
         if self.jobs_in_queue.contains(&id) {
@@ -137,6 +157,49 @@ pub struct Target {
     pub target_name: TargetName,
     /// Groups which given target belongs to
     pub groups: UnordEqVec<TargetGroup>,
+    /// Memory of the chip, the layout of the firmwares is not checked when it is unknown.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub memory_map: Vec<MemoryRegion>,
+}
+
+/// Kind of a memory region.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryKind {
+    /// Flash or other non-volatile memory.
+    Flash,
+    /// RAM.
+    Ram,
+    /// Any other memory.
+    Other,
+}
+
+/// A region of the memory of a chip.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// Kind of the region.
+    pub kind: MemoryKind,
+    /// Addresses of the region.
+    pub range: Range<u64>,
+}
+
+/// Usage of a target, accumulated by the server across restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TargetStats {
+    /// Number of times the target was flashed, runs writing the images to RAM do not count.
+    pub flash_operations: u64,
+    /// Bytes programmed into the flash.
+    pub bytes_programmed: u64,
+    /// Flash sectors erased.
+    pub sectors_erased: u64,
+    /// Number of runs on the target, including the ones that failed.
+    pub runs: u64,
+    /// Total time spent in runs, in milliseconds.
+    pub run_time_ms: u64,
+    /// Whether the target reached the flash operations warning threshold of the server and
+    /// should be replaced.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replacement_due: bool,
 }
 
 /// Vector wrapper which has a custom PartialEq implementation which ignores
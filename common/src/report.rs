@@ -0,0 +1,314 @@
+//! Rendering of job results in the formats consumed by CI systems.
+//!
+//! Every run of a task on a target is a test case, replaced by one test case per test when the
+//! firmware reported per-test outcomes (see [`RunResult::tests`]).
+
+use crate::job::{JobResult, LogRecord, RunResult, RunResultDetails, TestCase, TestStatus};
+use std::fmt::Write;
+
+/// Render `job_result` as JUnit XML.
+///
+/// There is one `<testsuite>` per run holding the logs of the run in its `<system-out>`.
+pub fn junit_xml(job_result: &JobResult) -> String {
+    let cases: Vec<_> = job_result
+        .tasks
+        .iter()
+        .flat_map(|task| task.runs.iter().map(move |run| (task.id, run)))
+        .map(|(task_id, run)| (task_id, run, cases(run)))
+        .collect();
+    let count = |status: fn(&Case) -> bool| {
+        cases
+            .iter()
+            .map(|(_, _, cases)| cases.iter().filter(|case| status(case)).count())
+            .sum::<usize>()
+    };
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">",
+        job_result.id,
+        count(|_| true),
+        count(|case| case.failure.is_some()),
+        count(|case| case.skipped.is_some()),
+    );
+    for (task_id, run, cases) in &cases {
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" package=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">",
+            escape(&suite_name(run)),
+            task_id,
+            cases.len(),
+            cases.iter().filter(|case| case.failure.is_some()).count(),
+            cases.iter().filter(|case| case.skipped.is_some()).count(),
+        );
+        for case in cases {
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\"",
+                escape(&case.name),
+                escape(&suite_name(run)),
+            );
+            if let Some(duration_ms) = case.duration_ms {
+                let _ = write!(xml, " time=\"{:.3}\"", duration_ms as f64 / 1000.0);
+            }
+            match (&case.failure, &case.skipped) {
+                (Some(failure), _) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                        escape(failure.lines().next().unwrap_or_default()),
+                        escape(failure),
+                    );
+                }
+                (None, Some(skipped)) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                        escape(skipped)
+                    );
+                }
+                (None, None) => xml.push_str("/>\n"),
+            }
+        }
+        let _ = writeln!(
+            xml,
+            "    <system-out>{}</system-out>\n  </testsuite>",
+            escape(&join_logs(logs(run)))
+        );
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Render `job_result` in the Test Anything Protocol, version 13.
+///
+/// Failures carry their message and the logs of their run in a YAML block.
+pub fn tap(job_result: &JobResult) -> String {
+    let cases: Vec<_> = job_result
+        .tasks
+        .iter()
+        .flat_map(|task| task.runs.iter())
+        .flat_map(|run| cases(run).into_iter().map(move |case| (run, case)))
+        .collect();
+
+    let mut tap = format!("TAP version 13\n1..{}\n", cases.len());
+    for (n, (run, case)) in cases.iter().enumerate() {
+        let name = format!("{} {}", suite_name(run), case.name).replace('#', "\\#");
+        match (&case.failure, &case.skipped) {
+            (Some(failure), _) => {
+                let _ = writeln!(tap, "not ok {} - {}", n + 1, name);
+                tap.push_str("  ---\n  message: |\n");
+                for line in failure.lines() {
+                    let _ = writeln!(tap, "    {}", line);
+                }
+                tap.push_str("  logs: |\n");
+                for record in logs(run) {
+                    let _ = writeln!(tap, "    {}", record);
+                }
+                tap.push_str("  ...\n");
+            }
+            (None, Some(skipped)) => {
+                let _ = writeln!(tap, "ok {} - {} # SKIP {}", n + 1, name, skipped);
+            }
+            (None, None) => {
+                let _ = writeln!(tap, "ok {} - {}", n + 1, name);
+            }
+        }
+    }
+    tap
+}
+
+/// A test case of either report.
+struct Case {
+    name: String,
+    duration_ms: Option<u64>,
+    failure: Option<String>,
+    skipped: Option<String>,
+}
+
+/// Test cases of a run: its tests when it has some, otherwise the run itself.
+///
+/// A run that failed without any of its tests failing gets a test case of its own, so the
+/// failure is not lost.
+fn cases(run: &RunResult) -> Vec<Case> {
+    let run_case = |name: &str| Case {
+        name: name.into(),
+        duration_ms: None,
+        failure: match &run.result {
+            RunResultDetails::Failure { reason, .. } => Some(reason.to_string()),
+            _ => None,
+        },
+        skipped: match &run.result {
+            RunResultDetails::Cancelled { .. } => Some("cancelled".into()),
+            _ => None,
+        },
+    };
+    if run.tests.is_empty() {
+        return vec![run_case("run")];
+    }
+
+    let mut cases: Vec<_> = run.tests.iter().map(|test| test_case(run, test)).collect();
+    let run_case = run_case("run");
+    if run_case.failure.is_some() && cases.iter().all(|case| case.failure.is_none()) {
+        cases.push(run_case);
+    }
+    cases
+}
+
+fn test_case(run: &RunResult, test: &TestCase) -> Case {
+    Case {
+        name: test.name.clone(),
+        duration_ms: Some(test.duration_ms),
+        failure: match (test.status, &run.result) {
+            (TestStatus::Failed, RunResultDetails::Failure { reason, .. }) => {
+                Some(test.message.clone().map_or(reason.to_string(), |message| {
+                    format!("{}\n{}", message, reason)
+                }))
+            }
+            (TestStatus::Failed, _) => Some(test.message.clone().unwrap_or_default()),
+            _ => None,
+        },
+        skipped: match test.status {
+            TestStatus::Ignored => Some("ignored".into()),
+            _ => None,
+        },
+    }
+}
+
+fn suite_name(run: &RunResult) -> String {
+    format!(
+        "{} ({})",
+        run.target.target_name.0, run.target.probe_serial.0
+    )
+}
+
+fn logs(run: &RunResult) -> &[LogRecord] {
+    match &run.result {
+        RunResultDetails::Failure { logs, .. }
+        | RunResultDetails::Success { logs, .. }
+        | RunResultDetails::Cancelled { logs } => logs,
+    }
+}
+
+fn join_logs(logs: &[LogRecord]) -> String {
+    logs.iter().map(|record| format!("{}\n", record)).collect()
+}
+
+/// Escape `s` for XML text and attributes, dropping the characters XML 1.0 cannot represent.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{RunFailure, TaskResult};
+    use crate::{ProbeAlias, ProbeSerial, Target, TargetName, Uuid};
+
+    fn run(probe_serial: &str, result: RunResultDetails, tests: Vec<TestCase>) -> RunResult {
+        RunResult {
+            target: Target {
+                probe_serial: ProbeSerial(probe_serial.into()),
+                probe_alias: ProbeAlias(String::new()),
+                target_name: TargetName("nRF52840_xxAA".into()),
+                groups: Default::default(),
+                memory_map: Vec::new(),
+            },
+            result,
+            tests,
+            flash_skipped: false,
+        }
+    }
+
+    fn job_result() -> JobResult {
+        let test = |name: &str, status| TestCase {
+            name: name.into(),
+            status,
+            duration_ms: 1500,
+            message: None,
+        };
+        JobResult {
+            id: Uuid::nil(),
+            tasks: vec![TaskResult {
+                id: Uuid::nil(),
+                runs: vec![
+                    run(
+                        "PROBE_1",
+                        RunResultDetails::Success {
+                            logs: vec![LogRecord::plain("<ok>")],
+                            exit_code: Some(0),
+                        },
+                        vec![],
+                    ),
+                    run(
+                        "PROBE_2",
+                        RunResultDetails::Failure {
+                            reason: RunFailure::Timeout,
+                            logs: vec![LogRecord::plain("stuck")],
+                        },
+                        vec![
+                            test("passes", TestStatus::Passed),
+                            test("hangs", TestStatus::Failed),
+                        ],
+                    ),
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn junit_has_a_case_per_run_or_per_test() {
+        let xml = junit_xml(&job_result());
+        assert!(
+            xml.contains("<testsuites name=\"00000000-0000-0000-0000-000000000000\" tests=\"3\" failures=\"1\" skipped=\"0\">"),
+            "{}",
+            xml
+        );
+        assert!(xml.contains("<testcase name=\"run\" classname=\"nRF52840_xxAA (PROBE_1)\"/>"));
+        assert!(
+            xml.contains("<system-out>&lt;ok&gt;\n</system-out>"),
+            "{}",
+            xml
+        );
+        assert!(xml.contains(
+            "<testcase name=\"passes\" classname=\"nRF52840_xxAA (PROBE_2)\" time=\"1.500\"/>"
+        ));
+        assert!(
+            xml.contains("<failure message=\"The firmware reached timeout\">The firmware reached timeout</failure>"),
+            "{}",
+            xml
+        );
+    }
+
+    #[test]
+    fn tap_reports_failures_with_their_logs() {
+        assert_eq!(
+            tap(&job_result()),
+            "TAP version 13\n\
+             1..3\n\
+             ok 1 - nRF52840_xxAA (PROBE_1) run\n\
+             ok 2 - nRF52840_xxAA (PROBE_2) passes\n\
+             not ok 3 - nRF52840_xxAA (PROBE_2) hangs\n  \
+             ---\n  \
+             message: |\n    \
+             The firmware reached timeout\n  \
+             logs: |\n    \
+             stuck\n  \
+             ...\n"
+        );
+    }
+}
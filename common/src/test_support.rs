@@ -0,0 +1,42 @@
+//! Fixtures shared by the tests of the crates of the workspace.
+
+use object::write::{Object, Symbol, SymbolSection};
+use object::{
+    Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+};
+
+/// Build a minimal ELF with a vector table, passing validation and accepted by the runner.
+///
+/// `main` is at `0x0`, `HardFault` at `0x4` and `TEST_DONE` at `0x8`, all 4 bytes long and made
+/// of `bkpt 0` instructions. `_SEGGER_RTT` is in `.data`.
+pub fn test_elf() -> Vec<u8> {
+    let mut elf = Object::new(BinaryFormat::Elf, Architecture::Arm, Endianness::Little);
+    let vector_table = elf.add_section(
+        Vec::new(),
+        b".vector_table".to_vec(),
+        SectionKind::ReadOnlyData,
+    );
+    elf.set_section_data(vector_table, vec![0u8; 16], 4);
+    let text = elf.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+    elf.set_section_data(text, [0x00, 0xbe].repeat(6), 4);
+    let data = elf.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+    elf.set_section_data(data, vec![0u8; 48], 4);
+    for (name, section, kind, value, size) in [
+        ("main", text, SymbolKind::Text, 0, 4),
+        ("HardFault", text, SymbolKind::Text, 4, 4),
+        ("TEST_DONE", text, SymbolKind::Text, 8, 4),
+        ("_SEGGER_RTT", data, SymbolKind::Data, 0, 48),
+    ] {
+        elf.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size,
+            kind,
+            scope: SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Section(section),
+            flags: SymbolFlags::None,
+        });
+    }
+    elf.write().unwrap()
+}
@@ -1,97 +1,308 @@
 use crate::{
+    backend::Backend,
     cli::{ProbeInfo, ServerConfigs},
+    defmt_test::TestTracker,
+    events::JobEvents,
+    flash_cache::FlashCache,
     runner,
+    stats::TargetStatsStore,
+    store::JobStore,
 };
 use embedded_ci_common::{
-    job::{self, RunResultDetails},
-    ProbeSerial, ServerStatus,
+    job::{self, RunFailure, RunResultDetails},
+    JobStatus, ProbeSerial, ServerStatus, Uuid,
 };
 use log::*;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::cell::RefCell;
+use std::sync::{
+    atomic::{self, AtomicBool},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
 };
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task::JoinSet};
+
+/// Handles shared by the scheduler, the jobs it runs and the REST API.
+#[derive(Clone)]
+pub struct Context {
+    /// Status of the jobs, as reported by the REST API
+    pub server_status: Arc<Mutex<ServerStatus>>,
+    /// Persisted jobs and results
+    pub job_store: Arc<Mutex<JobStore>>,
+    /// Status transitions and log lines of the running jobs
+    pub job_events: Arc<JobEvents>,
+    /// Usage of the targets
+    pub target_stats: Arc<Mutex<TargetStatsStore>>,
+    /// Access to the targets
+    pub backend: Arc<dyn Backend>,
+    /// Images last flashed on each target
+    pub flash_cache: Arc<FlashCache>,
+}
 
 /// Start the backend job given the run queue (link between REST API and embedded runner) and
 /// probe configs.
+///
+/// Jobs are started in the order they were received, except that a job whose targets are all
+/// free may overtake jobs that are still waiting for busy targets. A waiting job reserves its
+/// targets so it cannot be starved by the jobs queued after it.
+///
+/// Requests to cancel a job arriving over `cancel_job_rx` drop the job if it is still queued
+/// and stop its runs if it is already running. Either way a result is produced for the job.
+///
+/// `restored_jobs` are jobs reloaded from the job store, they are queued ahead of anything
+/// received over `register_job_rx`.
+///
+/// Status transitions and log lines of the running jobs are published to the job events of
+/// `context`, the usage of the targets is recorded into its target stats.
 pub async fn run(
+    restored_jobs: Vec<job::Job>,
     mut register_job_rx: mpsc::Receiver<job::Job>,
+    mut cancel_job_rx: mpsc::Receiver<Uuid>,
     finished_job_tx: mpsc::Sender<job::JobResult>,
-    server_status: Arc<Mutex<ServerStatus>>,
+    context: Context,
     probe_configs: HashMap<ProbeSerial, ProbeInfo>,
     server_configs: ServerConfigs,
 ) {
-    let max_target_timeout = server_configs.max_target_timeout;
+    let Context {
+        server_status,
+        job_store,
+        job_events,
+        ..
+    } = context.clone();
+    let max_target_timeout = Duration::from_secs(server_configs.max_target_timeout.0 as _);
+    let max_jobs_in_queue = server_configs.max_jobs_in_queue.0;
+    let probe_configs = Arc::new(probe_configs);
+    let mut pending_jobs = VecDeque::from(restored_jobs);
+    let mut busy_probes = HashSet::new();
+    let mut running_jobs = JoinSet::new();
+    let mut cancel_flags = HashMap::<Uuid, Arc<AtomicBool>>::new();
+    // Jobs cancelled while still sitting in the `register_job_rx` channel
+    let mut cancelled_in_channel = HashSet::new();
     loop {
-        let job = register_job_rx.recv().await.unwrap();
-        let job_id = job.id;
-        info!("{job_id}: received");
-        server_status.lock().unwrap().job_started(job_id);
-        let sync_barrier = crossbeam::sync::WaitGroup::new();
-        let mut job_result = job::JobResult::empty_from_job(&job);
-        let mut runs = Vec::new();
-        let timeout = Duration::from_secs(job.timeout.as_secs().min(max_target_timeout.0 as _));
-        let probe_mutex = Arc::new(Mutex::new(()));
-        for task in job.tasks.into_iter() {
-            for target in task.targets.into_iter() {
-                let probe_speed_khz = probe_configs
-                    .get(&target.probe_serial)
-                    .and_then(|pc| pc.probe_speed_khz);
-                let task_id = task.id;
-                let run_id = target.probe_serial.clone();
-                debug!("{job_id}/{task_id}/{run_id}: setting up");
-                runs.push((
-                    task_id,
-                    run_id.clone(),
-                    tokio::task::spawn_blocking({
-                        let task_binary = task.binary.clone();
-                        let sync_barrier = sync_barrier.clone();
-                        let probe_mutex = probe_mutex.clone();
-                        move || {
-                            debug!("{job_id}/{task_id}/{run_id}: started");
-                            let mut runner = runner::Runner::new(
-                                &task_binary,
-                                &target.target_name,
-                                &target.probe_serial,
-                                probe_speed_khz,
-                            )?;
-                            runner.run(&probe_mutex, sync_barrier, timeout)
-                        }
-                    }),
-                ));
+        tokio::select! {
+            // Only drain the channel while there is room, so it keeps limiting the queue length
+            job = register_job_rx.recv(), if pending_jobs.len() < max_jobs_in_queue => {
+                // If sender is closed program should terminate; thus unwrap()
+                let job: job::Job = job.unwrap();
+                info!("{}: received", job.id);
+                if cancelled_in_channel.remove(&job.id) {
+                    finish_cancelled_job(&job, &finished_job_tx, &server_status).await;
+                } else {
+                    pending_jobs.push_back(job);
+                }
+            }
+            Some(finished) = running_jobs.join_next() => {
+                let (job_result, probe_serials): (job::JobResult, HashSet<ProbeSerial>) =
+                    finished.unwrap();
+                let job_id = job_result.id;
+                info!("{job_id}: finished");
+                for probe_serial in probe_serials.iter() {
+                    busy_probes.remove(probe_serial);
+                }
+                cancel_flags.remove(&job_id);
+                // Should be ok to await here as a concurrent job is expected to pick the messages up quickly
+                match finished_job_tx.send(job_result).await {
+                    Ok(_) => server_status.lock().unwrap().job_finished(job_id),
+                    Err(error) => error!("Sending of the finished job failed: {:?}", error),
+                }
+            }
+            Some(job_id) = cancel_job_rx.recv() => {
+                if let Some(index) = pending_jobs.iter().position(|job| job.id == job_id) {
+                    info!("{job_id}: cancelled while in queue");
+                    // Cannot fail, index is within bounds
+                    let job = pending_jobs.remove(index).unwrap();
+                    finish_cancelled_job(&job, &finished_job_tx, &server_status).await;
+                } else if let Some(cancel_flag) = cancel_flags.get(&job_id) {
+                    info!("{job_id}: cancelled while running");
+                    cancel_flag.store(true, atomic::Ordering::Relaxed);
+                } else if let JobStatus::InQueue = server_status.lock().unwrap().job_status(job_id) {
+                    info!("{job_id}: cancelled before being received");
+                    cancelled_in_channel.insert(job_id);
+                } else {
+                    debug!("{job_id}: cancellation requested but the job is no longer active");
+                }
             }
         }
-        if let Err(e) = tokio::task::spawn_blocking(move || sync_barrier.wait()).await {
-            error!("Failed to join the blocking thread: {e}");
-        }
-        for (task_id, run_id, run) in runs.into_iter() {
-            let run_outcome_from_runner = run.await.unwrap();
-            info!("{job_id}/{task_id}/{run_id}: finished");
-            debug!(
-                "{job_id}/{task_id}/{run_id}: result: {:?}",
-                &run_outcome_from_runner
-            );
-            let run_result = job_result
-                .task_mut_by_id(task_id)
-                .unwrap()
-                .run_mut_by_probe_serial(&run_id)
-                .unwrap();
-            run_result.result = match run_outcome_from_runner {
-                Ok(logs) => RunResultDetails::Success { logs },
-                Err(error) => RunResultDetails::Failure {
-                    error: error.to_string(),
-                },
-            };
+
+        let mut reserved_probes = HashSet::new();
+        let mut index = 0;
+        while index < pending_jobs.len() {
+            let probe_serials = pending_jobs[index].probe_serials();
+            if probe_serials.is_disjoint(&busy_probes)
+                && probe_serials.is_disjoint(&reserved_probes)
+            {
+                // Cannot fail, index is within bounds
+                let job = pending_jobs.remove(index).unwrap();
+                debug!("{}: starting on {} target(s)", job.id, probe_serials.len());
+                server_status.lock().unwrap().job_started(job.id);
+                job_store.lock().unwrap().job_started(job.id);
+                job_events.publish(
+                    job.id,
+                    job::JobEvent::Status {
+                        status: JobStatus::Running,
+                    },
+                );
+                busy_probes.extend(probe_serials.iter().cloned());
+                let cancel_flag = Arc::new(AtomicBool::new(false));
+                cancel_flags.insert(job.id, cancel_flag.clone());
+                running_jobs.spawn({
+                    let probe_configs = probe_configs.clone();
+                    let context = context.clone();
+                    async move {
+                        let job_result = run_job(
+                            job,
+                            &probe_configs,
+                            context,
+                            cancel_flag,
+                            max_target_timeout,
+                        )
+                        .await;
+                        (job_result, probe_serials)
+                    }
+                });
+            } else {
+                reserved_probes.extend(probe_serials);
+                index += 1;
+            }
         }
-        // Should be ok to await here as a concurrent job is expected to pick the messages up quickly
-        match finished_job_tx.send(job_result).await {
-            Ok(_) => server_status.lock().unwrap().job_finished(job_id),
-            Err(error) => error!("Sending of the finished job failed: {:?}", error),
+    }
+}
+
+/// Publish the result of a job that was cancelled before any of its runs started.
+async fn finish_cancelled_job(
+    job: &job::Job,
+    finished_job_tx: &mpsc::Sender<job::JobResult>,
+    server_status: &Arc<Mutex<ServerStatus>>,
+) {
+    let mut job_result = job::JobResult::empty_from_job(job);
+    for run_result in job_result.tasks.iter_mut().flat_map(|t| t.runs.iter_mut()) {
+        run_result.result = RunResultDetails::Cancelled { logs: Vec::new() };
+    }
+    match finished_job_tx.send(job_result).await {
+        Ok(_) => server_status.lock().unwrap().job_cancelled(job.id),
+        Err(error) => error!("Sending of the cancelled job failed: {:?}", error),
+    }
+}
+
+/// Run all the tasks of a job on their targets simultaneously and collect the results.
+async fn run_job(
+    job: job::Job,
+    probe_configs: &HashMap<ProbeSerial, ProbeInfo>,
+    context: Context,
+    cancel_flag: Arc<AtomicBool>,
+    max_target_timeout: Duration,
+) -> job::JobResult {
+    let job_id = job.id;
+    let sync_barrier = crossbeam::sync::WaitGroup::new();
+    let mut job_result = job::JobResult::empty_from_job(&job);
+    let mut runs = Vec::new();
+    let timeout = job.timeout.min(max_target_timeout);
+    for task in job.tasks.into_iter() {
+        let task = Arc::new(task);
+        for target in task.targets.iter().cloned() {
+            let probe_speed_khz = probe_configs
+                .get(&target.probe_serial)
+                .and_then(|pc| pc.probe_speed_khz);
+            let task_id = task.id;
+            let run_id = target.probe_serial.clone();
+            debug!("{job_id}/{task_id}/{run_id}: setting up");
+            runs.push((
+                task_id,
+                run_id.clone(),
+                tokio::task::spawn_blocking({
+                    let task = task.clone();
+                    let sync_barrier = sync_barrier.clone();
+                    let context = context.clone();
+                    let cancel_flag = cancel_flag.clone();
+                    move || {
+                        debug!("{job_id}/{task_id}/{run_id}: started");
+                        let mut runner = match runner::Runner::new(
+                            &task,
+                            &target,
+                            probe_speed_khz,
+                            &context.flash_cache,
+                        ) {
+                            Ok(runner) => runner,
+                            Err(e) => {
+                                let reason = RunFailure::InvalidImage {
+                                    error: runner::error_chain(&e),
+                                };
+                                let error = runner::RunnerError::Failed(reason, Vec::new());
+                                return (Err(error), Vec::new(), false);
+                            }
+                        };
+                        let test_tracker = RefCell::new(TestTracker::new());
+                        let publish_log = |record: &job::LogRecord| {
+                            test_tracker.borrow_mut().record(record);
+                            context.job_events.publish(
+                                job_id,
+                                job::JobEvent::Log {
+                                    task_id,
+                                    probe_serial: target.probe_serial.clone(),
+                                    record: record.clone(),
+                                },
+                            )
+                        };
+                        let start = Instant::now();
+                        let outcome = runner.run(
+                            context.backend.as_ref(),
+                            sync_barrier,
+                            &cancel_flag,
+                            timeout,
+                            &publish_log,
+                        );
+                        if runner.attached() {
+                            context.target_stats.lock().unwrap().record_run(
+                                &target.probe_serial,
+                                runner.flash_report(),
+                                start.elapsed(),
+                            );
+                        }
+                        let tests = test_tracker.into_inner().finish(outcome.is_ok());
+                        (outcome, tests, runner.flash_skipped())
+                    }
+                }),
+            ));
         }
     }
+    if let Err(e) = tokio::task::spawn_blocking(move || sync_barrier.wait()).await {
+        error!("Failed to join the blocking thread: {e}");
+    }
+    for (task_id, run_id, run) in runs.into_iter() {
+        let (run_outcome_from_runner, tests, flash_skipped) = run.await.unwrap();
+        info!("{job_id}/{task_id}/{run_id}: finished");
+        debug!(
+            "{job_id}/{task_id}/{run_id}: result: {:?}",
+            &run_outcome_from_runner
+        );
+        let run_result = job_result
+            .task_mut_by_id(task_id)
+            .unwrap()
+            .run_mut_by_probe_serial(&run_id)
+            .unwrap();
+        run_result.result = match run_outcome_from_runner {
+            Ok(output) => RunResultDetails::Success {
+                logs: output.logs,
+                exit_code: output.exit_code,
+            },
+            Err(runner::RunnerError::Cancelled(logs)) => RunResultDetails::Cancelled { logs },
+            Err(runner::RunnerError::Failed(reason, logs)) => {
+                RunResultDetails::Failure { reason, logs }
+            }
+            Err(error) => RunResultDetails::Failure {
+                reason: RunFailure::Other {
+                    error: runner::error_chain(&error),
+                },
+                logs: Vec::new(),
+            },
+        };
+        run_result.tests = tests;
+        run_result.flash_skipped = flash_skipped;
+    }
+    job_result
 }
 
 // TODO: To be removed?
@@ -122,6 +333,8 @@ pub async fn finished_job_collector(
     finished_job_queue: Arc<Mutex<VecDeque<job::JobResult>>>,
     mut finished_job_rx: mpsc::Receiver<job::JobResult>,
     server_status: Arc<Mutex<ServerStatus>>,
+    job_store: Arc<Mutex<JobStore>>,
+    job_events: Arc<JobEvents>,
     max_jobs_in_queue: usize,
 ) {
     loop {
@@ -131,6 +344,8 @@ pub async fn finished_job_collector(
             "Moving the job result of id: {} into the finished queue",
             finished_job.id
         );
+        job_store.lock().unwrap().job_finished(&finished_job);
+        let finished_job_event = finished_job.clone();
         // Should never fail
         let mut finished_job_queue = finished_job_queue.lock().unwrap();
         match finished_job_queue.len().cmp(&max_jobs_in_queue) {
@@ -139,6 +354,7 @@ pub async fn finished_job_collector(
                 // Cannot fail, holding a mutex between the len check and pop_front
                 let dropped_job = finished_job_queue.pop_front().unwrap();
                 server_status.lock().unwrap().job_cleared(dropped_job.id);
+                job_store.lock().unwrap().job_cleared(dropped_job.id);
                 trace!(
                     "Queue full, dropping finished job with id: {}",
                     dropped_job.id
@@ -147,5 +363,1011 @@ pub async fn finished_job_collector(
             }
             Ordering::Greater => unreachable!("Queue length longer than max allowed"),
         }
+        drop(finished_job_queue);
+        job_events.finish(&finished_job_event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        simulated::{Script, SimulatedBackend, Step},
+        CoreRegister, HardFault,
+    };
+    use embedded_ci_common::{
+        fault::FaultStatus,
+        job::{
+            ExitCondition, ExitCriterion, ExitOutcome, InputStep, JobDesc, RttChannel, RttInput,
+            TaskDesc,
+        },
+        test_support::test_elf,
+        RunOn, Target, TargetName, TargetStats, Targets,
+    };
+
+    fn available_targets() -> Targets {
+        ["PROBE_SERIAL_1", "PROBE_SERIAL_2"]
+            .iter()
+            .map(|probe_serial| Target {
+                probe_serial: ProbeSerial(probe_serial.to_string()),
+                probe_alias: Default::default(),
+                target_name: TargetName("TARGET".into()),
+                groups: Default::default(),
+                memory_map: Vec::new(),
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn job_on(probe_serials: &[&str], timeout_secs: u32) -> job::Job {
+        let desc = JobDesc {
+            tasks: vec![TaskDesc {
+                run_on: vec![RunOn::ProbeSerials(
+                    probe_serials
+                        .iter()
+                        .map(|probe_serial| ProbeSerial(probe_serial.to_string()))
+                        .collect(),
+                )],
+                images: vec![job::ImageDesc {
+                    binary_b64: base64::encode(test_elf()),
+                    format: job::ImageFormat::Elf,
+                    primary: false,
+                }],
+                ..Default::default()
+            }],
+            timeout_secs,
+        };
+        job::Job::from_desc(desc, &available_targets()).unwrap()
+    }
+
+    fn context(backend: Arc<dyn Backend>) -> Context {
+        Context {
+            server_status: Arc::new(Mutex::new(ServerStatus::default())),
+            job_store: Arc::new(Mutex::new(JobStore::in_memory())),
+            job_events: Arc::new(JobEvents::new()),
+            target_stats: Arc::new(Mutex::new(TargetStatsStore::in_memory(None))),
+            backend,
+            flash_cache: Arc::new(FlashCache::new()),
+        }
+    }
+
+    async fn run_single_job(backend: SimulatedBackend, job: job::Job) -> job::JobResult {
+        run_job(
+            job,
+            &HashMap::new(),
+            context(Arc::new(backend)),
+            Arc::new(AtomicBool::new(false)),
+            Duration::from_secs(30),
+        )
+        .await
+    }
+
+    fn messages(logs: &[job::LogRecord]) -> Vec<&str> {
+        logs.iter().map(|record| record.message.as_str()).collect()
+    }
+
+    fn run_results(job_result: &job::JobResult) -> Vec<&RunResultDetails> {
+        job_result
+            .tasks
+            .iter()
+            .flat_map(|task| task.runs.iter().map(|run| &run.result))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn successful_run_collects_logs() {
+        let backend =
+            SimulatedBackend::new().with_target("PROBE_SERIAL_1", Script::success(b"hello\nworld"));
+        let job_result = run_single_job(backend, job_on(&["PROBE_SERIAL_1"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => {
+                assert_eq!(messages(logs), &["hello", "world"])
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn hardfault_fails_the_run() {
+        let status = FaultStatus::Armv7m {
+            hfsr: 1 << 30,
+            cfsr: 0x8200,
+            mmfar: None,
+            bfar: Some(0x2004_0000),
+        };
+        let fault = HardFault {
+            lr: 0xffff_fff9,
+            status: status.clone(),
+        };
+        // Halted at the entry of `HardFault`, which interrupted `main`
+        let mut registers: HashMap<_, _> = (0..16).map(|n| (CoreRegister::R(n), 0)).collect();
+        registers.insert(CoreRegister::R(13), 0x2000_0100);
+        registers.insert(CoreRegister::R(14), 0xffff_fff9);
+        registers.insert(CoreRegister::R(15), 0x4);
+        let stacked: [u32; 8] = [0, 0, 0, 0, 0, 0xffff_ffff, 0x2, 0x0100_0000];
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![Step::Rtt(b"before".to_vec()), Step::HardFault(fault)],
+                registers,
+                memory: vec![(
+                    0x2000_0100,
+                    stacked.iter().flat_map(|word| word.to_le_bytes()).collect(),
+                )],
+                ..Default::default()
+            },
+        );
+        let job_result = run_single_job(backend, job_on(&["PROBE_SERIAL_1"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure { reason, logs }] => {
+                assert_eq!(
+                    reason,
+                    &RunFailure::HardFault {
+                        status,
+                        lr: 0xffff_fff9,
+                        backtrace: vec![
+                            job::BacktraceFrame {
+                                address: 0x4,
+                                function: Some("HardFault".into()),
+                                ..Default::default()
+                            },
+                            job::BacktraceFrame {
+                                address: 0x2,
+                                function: Some("main".into()),
+                                ..Default::default()
+                            },
+                        ],
+                    }
+                );
+                assert!(reason.to_string().contains("0x20040000"), "{}", reason);
+                assert_eq!(messages(logs), &["before"]);
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    /// Script halting on the `bkpt` `instruction`, with `R0` and `R1` set to `arguments`.
+    fn breakpoint_script(
+        instruction: u16,
+        arguments: [u32; 2],
+        memory: Vec<(u32, Vec<u8>)>,
+    ) -> Script {
+        let registers = HashMap::from([
+            (CoreRegister::R(0), arguments[0]),
+            (CoreRegister::R(1), arguments[1]),
+            (CoreRegister::R(15), 0x2),
+        ]);
+        Script {
+            steps: vec![Step::Rtt(b"done".to_vec()), Step::Breakpoint],
+            registers,
+            memory: [vec![(0x2, instruction.to_le_bytes().to_vec())], memory].concat(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_codes_decide_the_outcome() {
+        // `bkpt 0x2a` and semihosting `SYS_EXIT_EXTENDED` with `ADP_Stopped_ApplicationExit, 3`
+        let block = [0x20026u32, 3]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let backend = SimulatedBackend::new()
+            .with_target("PROBE_SERIAL_1", breakpoint_script(0xbe2a, [0, 0], vec![]))
+            .with_target(
+                "PROBE_SERIAL_2",
+                breakpoint_script(0xbeab, [0x20, 0x2000_0000], vec![(0x2000_0000, block)]),
+            );
+        let job_result =
+            run_single_job(backend, job_on(&["PROBE_SERIAL_1", "PROBE_SERIAL_2"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::ExitCode { code: 42 },
+                logs,
+            }, RunResultDetails::Failure {
+                reason: RunFailure::ExitCode { code: 3 },
+                ..
+            }] => assert_eq!(messages(logs), &["done"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        // `bkpt 0` and semihosting `SYS_EXIT` with `ADP_Stopped_ApplicationExit`
+        let backend = SimulatedBackend::new()
+            .with_target("PROBE_SERIAL_1", breakpoint_script(0xbe00, [0, 0], vec![]))
+            .with_target(
+                "PROBE_SERIAL_2",
+                breakpoint_script(0xbeab, [0x18, 0x20026], vec![]),
+            );
+        let job_result =
+            run_single_job(backend, job_on(&["PROBE_SERIAL_1", "PROBE_SERIAL_2"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success {
+                exit_code: Some(0), ..
+            }, RunResultDetails::Success {
+                exit_code: Some(0), ..
+            }] => {}
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_semihosting_calls_fail() {
+        // Semihosting `SYS_WRITE0`, as made by `hprintln!`, and a halt whose cause is unreadable
+        let backend = SimulatedBackend::new()
+            .with_target(
+                "PROBE_SERIAL_1",
+                breakpoint_script(0xbeab, [0x04, 0x2000_0000], vec![]),
+            )
+            .with_target(
+                "PROBE_SERIAL_2",
+                Script {
+                    registers: [(CoreRegister::R(15), 0x2000_0000)].into(),
+                    ..Script::success(b"done")
+                },
+            );
+        let job_result =
+            run_single_job(backend, job_on(&["PROBE_SERIAL_1", "PROBE_SERIAL_2"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::Semihosting { operation: 0x04 },
+                logs,
+            }, RunResultDetails::Failure {
+                reason: RunFailure::Other { .. },
+                ..
+            }] => assert_eq!(messages(logs), &["done"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn breakpoints_outside_of_bkpt_fail() {
+        // Halted on `bx lr`
+        let backend = SimulatedBackend::new()
+            .with_target("PROBE_SERIAL_1", breakpoint_script(0x4770, [0, 0], vec![]));
+        let job_result = run_single_job(backend, job_on(&["PROBE_SERIAL_1"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::Halted { .. },
+                logs,
+            }] => assert_eq!(messages(logs), &["done"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn up_channels_are_captured_by_name() {
+        let script = Script {
+            steps: vec![
+                Step::RttOn(0, b"log\n".to_vec()),
+                Step::RttOn(1, b"telemetry\n".to_vec()),
+                Step::Breakpoint,
+            ],
+            up_channels: vec![Some("defmt".into()), None],
+            ..Default::default()
+        };
+        let run = |rtt_channels: Option<Vec<RttChannel>>| {
+            let backend = SimulatedBackend::new().with_target("PROBE_SERIAL_1", script.clone());
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].rtt_channels = rtt_channels;
+            run_single_job(backend, job)
+        };
+        let channels = |logs: &[job::LogRecord]| {
+            logs.iter()
+                .map(|record| (record.channel.clone().unwrap(), record.message.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let job_result = run(None).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(
+                channels(logs),
+                &[
+                    ("defmt".into(), "log".into()),
+                    ("1".into(), "telemetry".into()),
+                    ("defmt".into(), "".into()),
+                    ("1".into(), "".into())
+                ]
+            ),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(Some(vec![RttChannel::Number(1)])).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(
+                channels(logs),
+                &[("1".into(), "telemetry".into()), ("1".into(), "".into())]
+            ),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(Some(vec![RttChannel::Name("missing".into())])).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::RttChannelNotFound { channel },
+                ..
+            }] => assert_eq!(channel, "missing"),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn input_script_drives_the_firmware() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![
+                    Step::AwaitInput(b"hello\n".to_vec()),
+                    Step::Rtt(b"ready\n".to_vec()),
+                    Step::AwaitInput(vec![0x02, 0xff, 0x00]),
+                    Step::Rtt(b"started\n".to_vec()),
+                    Step::Breakpoint,
+                ],
+                ..Default::default()
+            },
+        );
+        let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+        job.tasks[0].rtt_input = Some(RttInput {
+            channel: RttChannel::Number(0),
+            steps: vec![
+                InputStep::Send("hello\n".into()),
+                InputStep::Expect("^re.dy$".into()),
+                InputStep::SendB64(base64::encode([0x02, 0xff, 0x00])),
+            ],
+        });
+        let job_result = run_single_job(backend, job).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => {
+                assert_eq!(messages(logs), &["ready", "started", ""])
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_criteria_end_the_run() {
+        let run = |steps: Vec<Step>, when: ExitCondition, outcome: ExitOutcome| {
+            let script = Script {
+                steps,
+                registers: [(CoreRegister::R(15), 0)].into(),
+                ..Default::default()
+            };
+            let backend = SimulatedBackend::new().with_target("PROBE_SERIAL_1", script);
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].exit_criteria = vec![ExitCriterion { when, outcome }];
+            run_single_job(backend, job)
+        };
+
+        let job_result = run(
+            vec![Step::Rtt(b"booting\nTEST_DONE\n".to_vec()), Step::Hang],
+            ExitCondition::LogMatches("^TEST_D.NE$".into()),
+            ExitOutcome::Pass,
+        )
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, exit_code }] => {
+                assert_eq!(messages(logs), &["booting", "TEST_DONE", ""]);
+                assert_eq!(exit_code, &None);
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(
+            vec![Step::Hang],
+            ExitCondition::AfterSecs(1),
+            ExitOutcome::Fail,
+        )
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::ExitCriterion { condition },
+                ..
+            }] => assert_eq!(condition, &ExitCondition::AfterSecs(1)),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(
+            vec![Step::Breakpoint],
+            ExitCondition::Symbol("main".into()),
+            ExitOutcome::Fail,
+        )
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::ExitCriterion { condition },
+                ..
+            }] => assert_eq!(condition, &ExitCondition::Symbol("main".into())),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(
+            vec![Step::Breakpoint],
+            ExitCondition::Symbol("NOT_IN_ELF".into()),
+            ExitOutcome::Pass,
+        )
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::InvalidImage { error },
+                ..
+            }] => assert!(error.contains("NOT_IN_ELF"), "{}", error),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_halts_beat_criteria_met_after_them() {
+        // The last line is only complete once the output is flushed after the halt
+        let run = |halt: Step, outcome: ExitOutcome| {
+            let script = Script {
+                steps: vec![Step::Rtt(b"booting\nTEST_DONE".to_vec()), halt],
+                ..Default::default()
+            };
+            let backend = SimulatedBackend::new().with_target("PROBE_SERIAL_1", script);
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].exit_criteria = vec![ExitCriterion {
+                when: ExitCondition::LogMatches("^TEST_DONE$".into()),
+                outcome,
+            }];
+            run_single_job(backend, job)
+        };
+
+        let fault = HardFault {
+            lr: 0xffff_fff9,
+            status: FaultStatus::Armv6m { stacked: None },
+        };
+        let job_result = run(Step::HardFault(fault), ExitOutcome::Pass).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::HardFault { .. },
+                logs,
+            }] => assert_eq!(messages(logs), &["booting", "TEST_DONE"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(Step::Breakpoint, ExitOutcome::Fail).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::ExitCriterion { condition },
+                ..
+            }] => assert_eq!(condition, &ExitCondition::LogMatches("^TEST_DONE$".into())),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn firmware_without_main_or_rtt() {
+        let run = |configure: fn(&mut job::Task)| {
+            let backend =
+                SimulatedBackend::new().with_target("PROBE_SERIAL_1", Script::success(b"hello"));
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            configure(&mut job.tasks[0]);
+            run_single_job(backend, job)
+        };
+
+        let job_result = run(|task| task.entry_symbol = Some("HardFault".into())).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(messages(logs), &["hello"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(|task| task.entry_symbol = Some("start".into())).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::InvalidImage { error },
+                ..
+            }] => assert!(error.contains("'start' symbol not found"), "{}", error),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(|task| {
+            task.entry_symbol = Some("start".into());
+            task.skip_run_to_entry = true;
+        })
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(messages(logs), &["hello"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(|task| task.no_rtt = true).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert!(logs.is_empty()),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn images_without_symbols() {
+        let run = |binary: &[u8], format: job::ImageFormat| {
+            let backend =
+                SimulatedBackend::new().with_target("PROBE_SERIAL_1", Script::success(b"hello"));
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].images = vec![job::Image {
+                binary: binary.to_vec(),
+                format,
+            }];
+            run_single_job(backend, job)
+        };
+
+        let bin = job::ImageFormat::Bin {
+            base_address: 0x0800_0000,
+        };
+        let job_result = run(&[0; 32], bin).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(messages(logs), &["hello"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        let job_result = run(b"not a hex file", job::ImageFormat::Ihex).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::InvalidImage { error },
+                ..
+            }] => assert!(error.contains("Intel HEX parsing error"), "{}", error),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn several_images() {
+        let run = |primary: usize| {
+            let backend =
+                SimulatedBackend::new().with_target("PROBE_SERIAL_1", Script::success(b"hello"));
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            let bootloader = job::Image {
+                binary: vec![0; 32],
+                format: job::ImageFormat::Bin {
+                    base_address: 0x0800_0000,
+                },
+            };
+            job.tasks[0].images.insert(0, bootloader);
+            job.tasks[0].primary = primary;
+            job.tasks[0].entry_symbol = Some("start".into());
+            run_single_job(backend, job)
+        };
+
+        // The symbols of the application are used to run to the entry
+        let job_result = run(1).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::InvalidImage { error },
+                ..
+            }] => assert!(error.contains("'start' symbol not found"), "{}", error),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        // The bootloader has no symbols, the run starts from reset
+        let job_result = run(0).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(messages(logs), &["hello"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn flashing_skipped_when_target_holds_images() {
+        let flash_cache = Arc::new(FlashCache::new());
+        let probe_configs = HashMap::new();
+        let contents: Vec<u8> = (0..32).collect();
+        let run = |memory: &[u8], fail_flash: bool, execution_mode: job::ExecutionMode| {
+            let backend = SimulatedBackend::new().with_target(
+                "PROBE_SERIAL_1",
+                Script {
+                    fail_flash,
+                    memory: vec![(0x0800_0000, memory.to_vec())],
+                    ..Script::success(b"hello")
+                },
+            );
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].images = vec![job::Image {
+                binary: contents.clone(),
+                format: job::ImageFormat::Bin {
+                    base_address: 0x0800_0000,
+                },
+            }];
+            job.tasks[0].execution_mode = execution_mode;
+            run_job(
+                job,
+                &probe_configs,
+                Context {
+                    flash_cache: flash_cache.clone(),
+                    ..context(Arc::new(backend))
+                },
+                Arc::new(AtomicBool::new(false)),
+                Duration::from_secs(30),
+            )
+        };
+        let flash_skipped = |job_result: &job::JobResult| job_result.tasks[0].runs[0].flash_skipped;
+
+        // Nothing is known about the target yet
+        let job_result = run(&contents, false, job::ExecutionMode::Flash).await;
+        assert!(!flash_skipped(&job_result));
+
+        // Flashing would fail, but the target already holds the image
+        let job_result = run(&contents, true, job::ExecutionMode::Flash).await;
+        assert!(flash_skipped(&job_result));
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success { logs, .. }] => assert_eq!(messages(logs), &["hello"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        // Images run from RAM do not survive the reset before the run
+        let job_result = run(&contents, false, job::ExecutionMode::Ram).await;
+        assert!(!flash_skipped(&job_result));
+
+        // The contents of the target changed since
+        let job_result = run(&[0; 32], false, job::ExecutionMode::Flash).await;
+        assert!(!flash_skipped(&job_result));
+    }
+
+    #[tokio::test]
+    async fn target_stats_accumulate() {
+        let stats_path = std::env::temp_dir().join(format!("target-stats-{}.json", Uuid::new_v4()));
+        let target_stats = Arc::new(Mutex::new(
+            TargetStatsStore::open(&stats_path, Some(2)).unwrap(),
+        ));
+        let probe_configs = HashMap::new();
+        let run_on = |script: Script, execution_mode: job::ExecutionMode| {
+            let backend = SimulatedBackend::new().with_target("PROBE_SERIAL_1", script);
+            let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+            job.tasks[0].images = vec![job::Image {
+                binary: vec![0; 1500],
+                format: job::ImageFormat::Bin {
+                    base_address: 0x0800_0000,
+                },
+            }];
+            job.tasks[0].execution_mode = execution_mode;
+            run_job(
+                job,
+                &probe_configs,
+                Context {
+                    target_stats: target_stats.clone(),
+                    ..context(Arc::new(backend))
+                },
+                Arc::new(AtomicBool::new(false)),
+                Duration::from_secs(30),
+            )
+        };
+        let run = |execution_mode| run_on(Script::success(b"hello"), execution_mode);
+        let probe_serial = ProbeSerial("PROBE_SERIAL_1".into());
+
+        run(job::ExecutionMode::Flash).await;
+        // Writing the images into RAM leaves the flash alone
+        run(job::ExecutionMode::Ram).await;
+        let stats = target_stats.lock().unwrap().get(&probe_serial);
+        assert_eq!(stats.runs, 2);
+        assert_eq!(stats.flash_operations, 1);
+        assert_eq!(stats.bytes_programmed, 1500);
+        assert_eq!(stats.sectors_erased, 2);
+        assert!(!stats.replacement_due);
+
+        // Nothing ran on a target that could not be attached to
+        let unreachable = Script {
+            fail_attach: true,
+            ..Default::default()
+        };
+        run_on(unreachable, job::ExecutionMode::Flash).await;
+        assert_eq!(target_stats.lock().unwrap().get(&probe_serial), stats);
+
+        run(job::ExecutionMode::Flash).await;
+        let stats = target_stats.lock().unwrap().get(&probe_serial);
+        assert_eq!(stats.runs, 3);
+        assert_eq!(stats.flash_operations, 2);
+        assert_eq!(stats.bytes_programmed, 3000);
+        assert_eq!(stats.sectors_erased, 4);
+        assert!(stats.replacement_due);
+
+        // The stats survive a restart
+        let reopened = TargetStatsStore::open(&stats_path, Some(2)).unwrap();
+        assert_eq!(reopened.get(&probe_serial), stats);
+        assert_eq!(
+            reopened.get(&ProbeSerial("PROBE_SERIAL_2".into())),
+            TargetStats::default()
+        );
+        std::fs::remove_file(stats_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hanging_firmware_times_out() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![Step::Rtt(b"started".to_vec()), Step::Hang],
+                ..Default::default()
+            },
+        );
+        let job_result = run_single_job(backend, job_on(&["PROBE_SERIAL_1"], 1)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure { reason, logs }] => {
+                assert_eq!(reason, &RunFailure::Timeout);
+                assert_eq!(messages(logs), &["started"]);
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_firmware_stalls() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![
+                    Step::Rtt(b"booting\n".to_vec()),
+                    Step::Delay(Duration::from_millis(600)),
+                    Step::Rtt(b"tick\n".to_vec()),
+                    Step::Delay(Duration::from_millis(600)),
+                    Step::Rtt(b"tick\n".to_vec()),
+                    Step::Hang,
+                ],
+                ..Default::default()
+            },
+        );
+        let mut job = job_on(&["PROBE_SERIAL_1"], 5);
+        job.tasks[0].inactivity_timeout_secs = Some(1);
+        let start = Instant::now();
+        let job_result = run_single_job(backend, job).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason:
+                    RunFailure::Stalled {
+                        silent_secs,
+                        last_lines,
+                    },
+                logs,
+            }] => {
+                assert_eq!(*silent_secs, 1);
+                assert_eq!(last_lines, &["booting", "tick", "tick", ""]);
+                assert_eq!(messages(logs), &["booting", "tick", "tick", ""]);
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_target_does_not_block_the_others() {
+        let backend = SimulatedBackend::new()
+            .with_target(
+                "PROBE_SERIAL_1",
+                Script {
+                    fail_flash: true,
+                    ..Default::default()
+                },
+            )
+            .with_target("PROBE_SERIAL_2", Script::success(b"ok"));
+        let job_result =
+            run_single_job(backend, job_on(&["PROBE_SERIAL_1", "PROBE_SERIAL_2"], 5)).await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Failure {
+                reason: RunFailure::FlashFailed { .. },
+                ..
+            }, RunResultDetails::Success { logs, .. }] => {
+                assert_eq!(messages(logs), &["ok"])
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn qemu_output_and_exit_status_are_captured() {
+        use crate::backend::{QemuBackend, QemuConfig};
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for `qemu-system-arm`, echoing the machine and failing on `bad-machine`
+        let binary = std::env::temp_dir().join(format!("fake-qemu-{}", Uuid::new_v4()));
+        std::fs::write(
+            &binary,
+            "#!/bin/sh\necho \"machine $2\"\n[ \"$2\" != bad-machine ] || exit 3\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = |machine: &str| QemuConfig {
+            machine: machine.into(),
+            cpu: None,
+            binary: binary.clone(),
+        };
+        let backend = Arc::new(QemuBackend::new(HashMap::from([
+            (ProbeSerial("PROBE_SERIAL_1".into()), config("lm3s6965evb")),
+            (ProbeSerial("PROBE_SERIAL_2".into()), config("bad-machine")),
+        ])));
+
+        let job_result = run_job(
+            job_on(&["PROBE_SERIAL_1", "PROBE_SERIAL_2"], 5),
+            &HashMap::new(),
+            context(backend),
+            Arc::new(AtomicBool::new(false)),
+            Duration::from_secs(30),
+        )
+        .await;
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Success {
+                logs,
+                exit_code: Some(0),
+            }, RunResultDetails::Failure {
+                reason: RunFailure::ExitCode { code: 3 },
+                ..
+            }] => {
+                assert_eq!(messages(logs), &["machine lm3s6965evb", ""]);
+            }
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+        std::fs::remove_file(binary).unwrap();
+    }
+
+    struct Server {
+        register_job_tx: mpsc::Sender<job::Job>,
+        cancel_job_tx: mpsc::Sender<Uuid>,
+        finished_job_rx: mpsc::Receiver<job::JobResult>,
+        server_status: Arc<Mutex<ServerStatus>>,
+        job_events: Arc<JobEvents>,
+    }
+
+    impl Server {
+        fn start(backend: SimulatedBackend) -> Self {
+            let (register_job_tx, register_job_rx) = mpsc::channel(10);
+            let (cancel_job_tx, cancel_job_rx) = mpsc::channel(10);
+            let (finished_job_tx, finished_job_rx) = mpsc::channel(10);
+            let context = context(Arc::new(backend));
+            let server_status = context.server_status.clone();
+            let job_events = context.job_events.clone();
+            tokio::spawn(run(
+                Vec::new(),
+                register_job_rx,
+                cancel_job_rx,
+                finished_job_tx,
+                context,
+                HashMap::new(),
+                ServerConfigs::default(),
+            ));
+            Self {
+                register_job_tx,
+                cancel_job_tx,
+                finished_job_rx,
+                server_status,
+                job_events,
+            }
+        }
+
+        async fn submit(&self, job: &job::Job) {
+            self.server_status.lock().unwrap().job_enqueued(job.id);
+            self.register_job_tx.send(job.clone()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_on_disjoint_targets_run_concurrently() {
+        let script = Script {
+            steps: vec![Step::Delay(Duration::from_secs(1)), Step::Breakpoint],
+            ..Default::default()
+        };
+        let backend = SimulatedBackend::new()
+            .with_target("PROBE_SERIAL_1", script.clone())
+            .with_target("PROBE_SERIAL_2", script);
+        let mut server = Server::start(backend);
+        let jobs = [
+            job_on(&["PROBE_SERIAL_1"], 5),
+            job_on(&["PROBE_SERIAL_2"], 5),
+            job_on(&["PROBE_SERIAL_1"], 5),
+        ];
+        let start = Instant::now();
+        for job in jobs.iter() {
+            server.submit(job).await;
+        }
+        let mut finished = Vec::new();
+        for _ in jobs.iter() {
+            finished.push(server.finished_job_rx.recv().await.unwrap().id);
+        }
+        // Sequentially this would take 3 seconds, the third job has to wait for the first one
+        assert!(start.elapsed() < Duration::from_millis(2800));
+        assert_eq!(finished[2], jobs[2].id);
+    }
+
+    #[tokio::test]
+    async fn running_and_queued_jobs_can_be_cancelled() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![Step::Rtt(b"running".to_vec()), Step::Hang],
+                ..Default::default()
+            },
+        );
+        let mut server = Server::start(backend);
+        let running = job_on(&["PROBE_SERIAL_1"], 30);
+        let queued = job_on(&["PROBE_SERIAL_1"], 30);
+        server.submit(&running).await;
+        server.submit(&queued).await;
+
+        server.cancel_job_tx.send(queued.id).await.unwrap();
+        let job_result = server.finished_job_rx.recv().await.unwrap();
+        assert_eq!(job_result.id, queued.id);
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Cancelled { logs }] => assert!(logs.is_empty()),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+
+        // Give the run a moment to start producing logs
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        server.cancel_job_tx.send(running.id).await.unwrap();
+        let job_result = server.finished_job_rx.recv().await.unwrap();
+        assert_eq!(job_result.id, running.id);
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Cancelled { logs }] => assert_eq!(messages(logs), &["running"]),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancellation_stops_flashing_between_images() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                flash_time: Duration::from_secs(1),
+                steps: vec![Step::Rtt(b"running".to_vec()), Step::Hang],
+                ..Default::default()
+            },
+        );
+        let mut server = Server::start(backend);
+        let mut job = job_on(&["PROBE_SERIAL_1"], 30);
+        job.tasks[0].images.push(job::Image {
+            binary: vec![0; 8],
+            format: job::ImageFormat::Bin {
+                base_address: 0x1000_0000,
+            },
+        });
+        let start = Instant::now();
+        server.submit(&job).await;
+
+        // Cancel while the first image is being flashed, the second one must not be
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        server.cancel_job_tx.send(job.id).await.unwrap();
+        let job_result = server.finished_job_rx.recv().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1800));
+        match run_results(&job_result)[..] {
+            [RunResultDetails::Cancelled { logs }] => assert!(logs.is_empty()),
+            ref v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_are_streamed_while_running() {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                steps: vec![
+                    Step::Rtt(b"first\n".to_vec()),
+                    Step::Delay(Duration::from_millis(500)),
+                    Step::Rtt(b"second".to_vec()),
+                    Step::Breakpoint,
+                ],
+                ..Default::default()
+            },
+        );
+        let mut server = Server::start(backend);
+        let job = job_on(&["PROBE_SERIAL_1"], 5);
+        let finished_job_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let mut events = match server.job_events.subscribe(job.id, &finished_job_queue) {
+            crate::events::Subscription::Live(events) => events,
+            crate::events::Subscription::Finished(_) => panic!("job has not been submitted yet"),
+        };
+        server.submit(&job).await;
+
+        match events.recv().await.unwrap() {
+            job::JobEvent::Status {
+                status: JobStatus::Running,
+            } => {}
+            v => panic!("unexpected event: {:?}", v),
+        }
+        match events.recv().await.unwrap() {
+            job::JobEvent::Log { record, .. } => assert_eq!(record.message, "first"),
+            v => panic!("unexpected event: {:?}", v),
+        }
+        // The first line arrives while the target is still running
+        assert!(server.finished_job_rx.try_recv().is_err());
+        match events.recv().await.unwrap() {
+            job::JobEvent::Log { record, .. } => assert_eq!(record.message, "second"),
+            v => panic!("unexpected event: {:?}", v),
+        }
+        server.finished_job_rx.recv().await.unwrap();
     }
 }
@@ -0,0 +1,209 @@
+//! Backends through which the runner drives the targets.
+//!
+//! The [`runner::Runner`](crate::runner::Runner) only decides what to do with a target, the
+//! actual access to it happens through a [`Backend`].
+
+mod probe;
+mod qemu;
+#[cfg(test)]
+pub mod simulated;
+
+pub use probe::ProbeRsBackend;
+pub use qemu::{QemuBackend, QemuConfig};
+
+use crate::runner::RunnerError;
+use embedded_ci_common::image::ImageInfo;
+use embedded_ci_common::job::{ExecutionMode, Image};
+use embedded_ci_common::{fault::FaultStatus, ProbeSerial, TargetName};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Internal helper to keep addresses and raw `u32`s apart.
+#[derive(Clone, Copy, Debug)]
+pub struct Address(pub u32);
+
+/// Core registers the backtrace unwinder reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CoreRegister {
+    /// One of `R0`-`R15`, `R13` being the stack pointer currently in use.
+    R(u16),
+    /// Process stack pointer.
+    Psp,
+}
+
+/// Holds important symbol addresses.
+pub struct Symbols {
+    /// Where the firmware is run to before the run starts, `None` to start right from reset.
+    pub entry: Option<Address>,
+    /// The RTT control block, `None` when it has to be searched for in RAM.
+    pub rtt: Option<Address>,
+}
+
+/// Holds important vector table addresses.
+pub struct VectorTable {
+    pub start: Address,
+    pub stack_pointer: Address,
+    pub reset: Address,
+    pub hardfault: Address,
+}
+
+/// Everything a backend needs to know about a firmware in order to flash and start it.
+pub struct Firmware<'a> {
+    /// Images flashed in one session, in order.
+    pub images: &'a [Image],
+    /// The image whose symbols drive the run, one of `images`.
+    pub primary: &'a Image,
+    /// What is known about each of the images, in order.
+    pub info: Vec<ImageInfo>,
+    /// Whether the images are flashed or written straight into RAM.
+    pub execution_mode: ExecutionMode,
+    /// Whether the firmware runs from RAM, when written into RAM or linked to run from RAM.
+    pub from_ram: bool,
+    pub symbols: Symbols,
+    pub vector_table: VectorTable,
+    /// Addresses the core is halted at, on top of the HardFault handler.
+    pub breakpoints: Vec<Address>,
+}
+
+/// What flashing a firmware did to the flash memory of a target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlashReport {
+    pub bytes_programmed: u64,
+    pub sectors_erased: u64,
+}
+
+/// An RTT channel of the running firmware.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RttChannelInfo {
+    pub number: usize,
+    /// Name the firmware gave the channel, `defmt-rtt` names its channel `defmt`.
+    pub name: Option<String>,
+}
+
+/// The RTT channels of the running firmware.
+#[derive(Clone, Debug, Default)]
+pub struct RttChannels {
+    /// Channels from the target to the host.
+    pub up: Vec<RttChannelInfo>,
+    /// Channels from the host to the target.
+    pub down: Vec<RttChannelInfo>,
+}
+
+/// Reason why a started target stopped running.
+#[derive(Clone, Debug)]
+pub enum Halt {
+    /// Halted on a breakpoint outside of the HardFault handler.
+    Breakpoint,
+    /// The firmware exited with the given code, for backends that see the exit itself.
+    Exit(i32),
+    /// Halted in the HardFault handler.
+    HardFault(HardFault),
+    /// Core locked up.
+    LockedUp,
+    /// Halted for any other reason.
+    Other(String),
+}
+
+/// Fault state captured when the target halts in the HardFault handler.
+#[derive(Clone, Debug)]
+pub struct HardFault {
+    /// Link register at the time of the halt.
+    pub lr: u32,
+    /// Fault status, read according to the architecture of the core.
+    pub status: FaultStatus,
+}
+
+/// Source of connections to targets.
+pub trait Backend: Send + Sync {
+    /// Attach to the target connected to the probe with `probe_serial`.
+    fn attach(
+        &self,
+        target_name: &TargetName,
+        probe_serial: &ProbeSerial,
+        probe_speed_khz: Option<u32>,
+    ) -> Result<Box<dyn Connection>, RunnerError>;
+}
+
+/// An attached target.
+///
+/// The methods are called by the runner in the order they are declared in.
+pub trait Connection {
+    /// Flash the firmware into the target.
+    ///
+    /// Returns `None` when no flash memory was written to, as for firmwares written into RAM or
+    /// virtual targets.
+    ///
+    /// Once `cancel_flag` is set, flashing stops before the next image with
+    /// [`RunnerError::Cancelled`]; an image being written is always written completely.
+    fn flash(
+        &mut self,
+        firmware: &Firmware,
+        cancel_flag: &AtomicBool,
+    ) -> Result<Option<FlashReport>, RunnerError>;
+
+    /// Bring the target to the start of the firmware (its entry when running from flash) and arm
+    /// the HardFault breakpoint along with the breakpoints of the firmware. The core is left
+    /// halted.
+    fn prepare(&mut self, firmware: &Firmware) -> Result<(), RunnerError>;
+
+    /// Let the core run.
+    fn run(&mut self) -> Result<(), RunnerError>;
+
+    /// Attach to the RTT control block of the running firmware, returns its channels.
+    fn attach_rtt(&mut self, firmware: &Firmware) -> Result<RttChannels, RunnerError>;
+
+    /// Read from the RTT up channel `channel`, returns the number of bytes read.
+    fn read_rtt(&mut self, channel: usize, buffer: &mut [u8]) -> Result<usize, RunnerError>;
+
+    /// Write to the RTT down channel `channel`, returns the number of bytes written.
+    fn write_rtt(&mut self, channel: usize, bytes: &[u8]) -> Result<usize, RunnerError>;
+
+    /// Check whether the core has stopped running and why. `None` while it is still running.
+    fn halt_status(&mut self) -> Result<Option<Halt>, RunnerError>;
+
+    /// Halt the core, used to stop a run early.
+    fn halt(&mut self) -> Result<(), RunnerError>;
+
+    /// Read a core register of the halted core.
+    fn read_core_register(&mut self, register: CoreRegister) -> Result<u32, RunnerError>;
+
+    /// Read target memory starting at `address` into `buffer`.
+    fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), RunnerError>;
+}
+
+/// Backend handing each probe over to the backend responsible for it.
+pub struct Router {
+    routes: HashMap<ProbeSerial, Arc<dyn Backend>>,
+    default: Arc<dyn Backend>,
+}
+
+impl Router {
+    /// Create a router sending all the probes to `default`.
+    pub fn new(default: Arc<dyn Backend>) -> Self {
+        Self {
+            routes: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Send the probe with `probe_serial` to `backend` instead.
+    pub fn route(mut self, probe_serial: ProbeSerial, backend: Arc<dyn Backend>) -> Self {
+        self.routes.insert(probe_serial, backend);
+        self
+    }
+}
+
+impl Backend for Router {
+    fn attach(
+        &self,
+        target_name: &TargetName,
+        probe_serial: &ProbeSerial,
+        probe_speed_khz: Option<u32>,
+    ) -> Result<Box<dyn Connection>, RunnerError> {
+        self.routes
+            .get(probe_serial)
+            .unwrap_or(&self.default)
+            .attach(target_name, probe_serial, probe_speed_khz)
+    }
+}
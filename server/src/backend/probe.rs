@@ -0,0 +1,524 @@
+use super::{
+    Backend, Connection, CoreRegister, Firmware, FlashReport, Halt, HardFault, RttChannelInfo,
+    RttChannels,
+};
+use crate::app::unroll_error;
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::fault::{ExceptionFrame, FaultStatus};
+use embedded_ci_common::job::{ExecutionMode, ImageFormat};
+use embedded_ci_common::{ProbeSerial, TargetName};
+use log::*;
+use probe_rs::flashing::{BinOptions, DownloadOptions, FlashProgress, ProgressEvent};
+use probe_rs::rtt::{DownChannel, Error as RttError, Rtt, ScanRegion, UpChannel};
+use probe_rs::{
+    Core, CoreStatus, CoreType, DebugProbeError, HaltReason, Probe, ProbeCreationError,
+};
+use probe_rs::{MemoryInterface, RegisterId, Session};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THUMB_BIT: u32 = 1;
+const SP: RegisterId = RegisterId(13);
+const LR: RegisterId = RegisterId(14);
+const PC: RegisterId = RegisterId(15);
+const PSR: RegisterId = RegisterId(16);
+const PSP: RegisterId = RegisterId(0b10010);
+const CPUID: u32 = 0xE000_ED00;
+/// `ARCHITECTURE` field of the CPUID of ARMv7-M and ARMv8-M Mainline cores.
+const CPUID_ARCHITECTURE_MAINLINE: u32 = 0xf;
+const VTOR: u32 = 0xE000ED08;
+const CFSR: u32 = 0xE000_ED28;
+const HFSR: u32 = 0xE000_ED2C;
+const MMFAR: u32 = 0xE000_ED34;
+const BFAR: u32 = 0xE000_ED38;
+const SFSR: u32 = 0xE000_EDE4;
+const SFAR: u32 = 0xE000_EDE8;
+const CFSR_MMARVALID: u32 = 1 << 7;
+const CFSR_BFARVALID: u32 = 1 << 15;
+const SFSR_SFARVALID: u32 = 1 << 6;
+/// Set in `EXC_RETURN` when the exception frame was pushed to the process stack.
+const EXC_RETURN_PROCESS_STACK: u32 = 1 << 2;
+
+/// Backend driving physical targets through debug probes with `probe-rs`.
+#[derive(Default)]
+pub struct ProbeRsBackend {
+    // Access to the list of probes needs to be unique, else the workers crash into each other.
+    probe_mutex: Mutex<()>,
+}
+
+impl ProbeRsBackend {
+    /// Create a new `probe-rs` backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the probe with the given serial number.
+    fn get_probe(
+        &self,
+        probe_serial: &ProbeSerial,
+        probe_speed_khz: Option<u32>,
+    ) -> Result<Probe, RunnerError> {
+        let guard = self.probe_mutex.lock().unwrap();
+        let probe = {
+            let all_probes = Probe::list_all();
+            let mut probe = all_probes
+                .iter()
+                .find(|probe| {
+                    if let Some(serial) = &probe.serial_number {
+                        &probe_serial.0 == serial
+                    } else {
+                        false
+                    }
+                })
+                .ok_or(DebugProbeError::ProbeCouldNotBeCreated(
+                    ProbeCreationError::NotFound,
+                ))?
+                .open()?;
+
+            if let Some(khz) = probe_speed_khz {
+                if let Err(e) = probe.set_speed(khz) {
+                    error!(
+                        "{}; Unable to set probe speed, error: {}",
+                        probe_serial,
+                        unroll_error(&e)
+                    );
+                }
+            }
+
+            probe
+        };
+        drop(guard);
+
+        Ok(probe)
+    }
+}
+
+impl Backend for ProbeRsBackend {
+    fn attach(
+        &self,
+        target_name: &TargetName,
+        probe_serial: &ProbeSerial,
+        probe_speed_khz: Option<u32>,
+    ) -> Result<Box<dyn Connection>, RunnerError> {
+        let probe = self.get_probe(probe_serial, probe_speed_khz)?;
+
+        debug!("{}: Attaching to target", probe_serial);
+        // First we try to connect normally
+        let session = match probe.attach(&target_name.0, Default::default()) {
+            Ok(v) => v,
+            Err(e) => {
+                // If that fails we fall back to a connect under reset attach
+                warn!(
+                    "{}: Attach failed ({}), trying with attach under reset...",
+                    probe_serial, e
+                );
+
+                let probe = self.get_probe(probe_serial, probe_speed_khz)?;
+                probe
+                    .attach_under_reset(&target_name.0, Default::default())
+                    .map_err(|_| {
+                        anyhow!(
+                        "Unable to attach to the target, both normal and attach under reset failed"
+                    )
+                    })?
+            }
+        };
+
+        Ok(Box::new(ProbeRsConnection {
+            probe_serial: probe_serial.clone(),
+            session,
+            up_channels: Vec::new(),
+            down_channels: Vec::new(),
+        }))
+    }
+}
+
+/// A target attached through `probe-rs`.
+struct ProbeRsConnection {
+    probe_serial: ProbeSerial,
+    session: Session,
+    up_channels: Vec<UpChannel>,
+    down_channels: Vec<DownChannel>,
+}
+
+impl Connection for ProbeRsConnection {
+    fn flash(
+        &mut self,
+        firmware: &Firmware,
+        cancel_flag: &AtomicBool,
+    ) -> Result<Option<FlashReport>, RunnerError> {
+        let cancelled = || match cancel_flag.load(Ordering::Relaxed) {
+            true => Err(RunnerError::Cancelled(Vec::new())),
+            false => Ok(()),
+        };
+        debug!("{}: Starting download of the images", self.probe_serial);
+        self.session
+            .core(0)?
+            .reset_and_halt(Duration::from_secs(3))?;
+
+        if firmware.execution_mode == ExecutionMode::Ram {
+            debug!("{}: Writing the images to RAM", self.probe_serial);
+            let mut core = self.session.core(0)?;
+            for info in firmware.info.iter() {
+                cancelled()?;
+                for section in info.sections.iter() {
+                    if let Some(load_range) = &section.load_range {
+                        core.write_8(load_range.start, &section.data)?;
+                    }
+                }
+            }
+            return Ok(None);
+        }
+
+        let report = Arc::new(Mutex::new(FlashReport::default()));
+        let mut opt = DownloadOptions::default();
+        opt.verify = true;
+        opt.keep_unwritten_bytes = true;
+        opt.progress = Some(FlashProgress::new({
+            let report = report.clone();
+            move |event| {
+                let mut report = report.lock().unwrap();
+                match event {
+                    ProgressEvent::SectorErased { .. } => report.sectors_erased += 1,
+                    ProgressEvent::PageProgrammed { size, .. } => {
+                        report.bytes_programmed += u64::from(size)
+                    }
+                    _ => {}
+                }
+            }
+        }));
+
+        let mut loader = self.session.target().flash_loader();
+        for image in firmware.images {
+            cancelled()?;
+            let mut binary = Cursor::new(&image.binary);
+            match image.format {
+                ImageFormat::Elf => loader.load_elf_data(&mut binary)?,
+                ImageFormat::Ihex => loader.load_hex_data(&mut binary)?,
+                ImageFormat::Bin { base_address } => loader.load_bin_data(
+                    &mut binary,
+                    BinOptions {
+                        base_address: Some(base_address),
+                        skip: 0,
+                    },
+                )?,
+            }
+        }
+
+        // The images are only written by the commit, which cannot be interrupted
+        cancelled()?;
+        loader.commit(&mut self.session, opt)?;
+        debug!("{}: Done!", self.probe_serial);
+
+        let report = *report.lock().unwrap();
+        Ok(Some(report))
+    }
+
+    fn prepare(&mut self, firmware: &Firmware) -> Result<(), RunnerError> {
+        let mut core = self.session.core(0)?;
+
+        if firmware.from_ram {
+            // Fix for ECC RAM, do a dummy write. Thanks to @dirbaio for finding
+            let data = core.read_word_32(firmware.vector_table.start.0 as _)?;
+            core.write_word_32(firmware.vector_table.start.0 as _, data)?;
+        }
+
+        core.reset_and_halt(Duration::from_secs(3))?;
+
+        // Check so we have some breakpoint units
+        if core.available_breakpoint_units()? == 0 {
+            error!(
+                "{}: The target does not have any HW breakpoint units?!?! Aborting.",
+                self.probe_serial
+            );
+            return Err(anyhow!(
+                "The target does not have any HW breakpoint units?! Aborting."
+            ))?;
+        }
+
+        debug!("{}: Starting target", self.probe_serial);
+        if firmware.from_ram {
+            debug!(
+                "{}: Running from RAM (will not halt at main)",
+                self.probe_serial
+            );
+            core.write_core_reg(PC, firmware.vector_table.reset.0)
+                .map_err(RunnerError::UnableToReachMain)?;
+            core.write_core_reg(SP, firmware.vector_table.stack_pointer.0)
+                .map_err(RunnerError::UnableToReachMain)?;
+            core.write_word_32(VTOR as _, firmware.vector_table.start.0)
+                .map_err(RunnerError::UnableToReachMain)?;
+        } else {
+            // Reset the RTT control block
+            if let Some(rtt) = firmware.symbols.rtt {
+                core.write_word_32(rtt.0 as _, 0x12341234)
+                    .map_err(RunnerError::UnableToReachMain)?;
+            }
+
+            if let Some(entry) = firmware.symbols.entry {
+                // Go to the entry, `main` unless the task chose another symbol
+                core.set_hw_breakpoint(entry.0 as _)
+                    .map_err(RunnerError::UnableToReachMain)?;
+
+                core.run().map_err(RunnerError::UnableToReachMain)?;
+                core.wait_for_core_halted(Duration::from_secs(5))
+                    .map_err(RunnerError::UnableToReachMain)?;
+                if let Some(rtt) = firmware.symbols.rtt {
+                    const OFFSET: u32 = 44;
+                    const FLAG: u32 = 2; // BLOCK_IF_FULL
+                    core.write_word_32((rtt.0 + OFFSET) as u64, FLAG)?;
+                }
+                debug!("{}: Arrived at the entry", self.probe_serial);
+                core.clear_hw_breakpoint(entry.0 as _)?;
+            } else {
+                debug!(
+                    "{}: Starting from reset (will not halt at the entry)",
+                    self.probe_serial
+                );
+            }
+        }
+
+        if firmware.from_ram {
+            // We can set breakpoints in RAM so we replace the instruction at the breakpoint
+            // location with the breakpoint instruction instead.
+            core.write_8(
+                (firmware.vector_table.hardfault.0 & !THUMB_BIT) as u64,
+                &[0x00, 0xbe],
+            )?;
+        } else {
+            core.set_hw_breakpoint((firmware.vector_table.hardfault.0 & !THUMB_BIT) as u64)?;
+        }
+
+        for breakpoint in &firmware.breakpoints {
+            if firmware.from_ram {
+                core.write_8(breakpoint.0 as u64, &[0x00, 0xbe])?;
+            } else {
+                core.set_hw_breakpoint(breakpoint.0 as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), RunnerError> {
+        self.session.core(0)?.run()?;
+        Ok(())
+    }
+
+    /// Helper function to set up RTT channels and compensate for common errors.
+    fn attach_rtt(&mut self, firmware: &Firmware) -> Result<RttChannels, RunnerError> {
+        debug!("{}: Starting RTT pipe", self.probe_serial);
+        let memory_map = self.session.target().memory_map.clone();
+        let mut core = self.session.core(0)?;
+        let start = Instant::now();
+
+        let scan_region = match firmware.symbols.rtt {
+            Some(rtt) => ScanRegion::Exact(rtt.0),
+            None => {
+                debug!(
+                    "{}: No '_SEGGER_RTT' symbol, scanning RAM for the control block",
+                    self.probe_serial
+                );
+                ScanRegion::Ram
+            }
+        };
+
+        let mut rtt = loop {
+            match Rtt::attach_region(&mut core, &memory_map, &scan_region) {
+                Ok(rtt) => break rtt,
+                Err(RttError::ControlBlockNotFound) => {
+                    thread::sleep(Duration::from_millis(10));
+                    if Instant::now() - start > Duration::from_secs(3) {
+                        return Err(RttError::ControlBlockNotFound.into());
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        self.up_channels = rtt.up_channels().drain().collect();
+        self.down_channels = rtt.down_channels().drain().collect();
+
+        Ok(RttChannels {
+            up: self
+                .up_channels
+                .iter()
+                .map(|channel| RttChannelInfo {
+                    number: channel.number(),
+                    name: channel.name().map(String::from),
+                })
+                .collect(),
+            down: self
+                .down_channels
+                .iter()
+                .map(|channel| RttChannelInfo {
+                    number: channel.number(),
+                    name: channel.name().map(String::from),
+                })
+                .collect(),
+        })
+    }
+
+    fn read_rtt(&mut self, channel: usize, buffer: &mut [u8]) -> Result<usize, RunnerError> {
+        let channel = self
+            .up_channels
+            .iter()
+            .find(|c| c.number() == channel)
+            .ok_or(anyhow!("RTT up channel {} is not attached", channel))?;
+        let mut core = self.session.core(0)?;
+        Ok(channel.read(&mut core, buffer)?)
+    }
+
+    fn write_rtt(&mut self, channel: usize, bytes: &[u8]) -> Result<usize, RunnerError> {
+        let channel = self
+            .down_channels
+            .iter()
+            .find(|c| c.number() == channel)
+            .ok_or(anyhow!("RTT down channel {} is not attached", channel))?;
+        let mut core = self.session.core(0)?;
+        Ok(channel.write(&mut core, bytes)?)
+    }
+
+    fn halt_status(&mut self) -> Result<Option<Halt>, RunnerError> {
+        let mut core = self.session.core(0)?;
+
+        Ok(match core.status()? {
+            CoreStatus::Halted(HaltReason::Breakpoint(_)) => {
+                let isr_no = core.read_core_reg::<u32>(PSR)? & 0xff;
+
+                if isr_no == 3 {
+                    warn!("{}: Halted due to hardfault", self.probe_serial);
+                    let lr = core.read_core_reg::<u32>(LR)?;
+                    let status = match core.core_type() {
+                        CoreType::Armv6m => stacked_fault_status(&mut core, lr, &self.probe_serial),
+                        // ARMv8-M Baseline has no fault status registers either
+                        CoreType::Armv8m if !has_main_extension(&mut core)? => {
+                            stacked_fault_status(&mut core, lr, &self.probe_serial)
+                        }
+                        CoreType::Armv8m => {
+                            let (hfsr, cfsr, mmfar, bfar) = read_fault_status(&mut core)?;
+                            let sfsr = core.read_word_32(SFSR as u64)?;
+                            let sfar = if sfsr & SFSR_SFARVALID != 0 {
+                                Some(core.read_word_32(SFAR as u64)?)
+                            } else {
+                                None
+                            };
+                            FaultStatus::Armv8m {
+                                hfsr,
+                                cfsr,
+                                mmfar,
+                                bfar,
+                                sfsr,
+                                sfar,
+                            }
+                        }
+                        CoreType::Armv7m | CoreType::Armv7em => {
+                            let (hfsr, cfsr, mmfar, bfar) = read_fault_status(&mut core)?;
+                            FaultStatus::Armv7m {
+                                hfsr,
+                                cfsr,
+                                mmfar,
+                                bfar,
+                            }
+                        }
+                        core_type => FaultStatus::Unsupported {
+                            architecture: format!("{:?}", core_type),
+                        },
+                    };
+
+                    Some(Halt::HardFault(HardFault { lr, status }))
+                } else {
+                    debug!("{}: Halted due to breakpoint", self.probe_serial);
+                    Some(Halt::Breakpoint)
+                }
+            }
+            CoreStatus::Halted(h) => Some(Halt::Other(format!("{:?}", h))),
+            CoreStatus::LockedUp => Some(Halt::LockedUp),
+            CoreStatus::Running | CoreStatus::Sleeping | CoreStatus::Unknown => None,
+        })
+    }
+
+    fn halt(&mut self) -> Result<(), RunnerError> {
+        self.session.core(0)?.halt(Duration::from_secs(1))?;
+        Ok(())
+    }
+
+    fn read_core_register(&mut self, register: CoreRegister) -> Result<u32, RunnerError> {
+        let id = match register {
+            CoreRegister::R(n) => RegisterId(n),
+            CoreRegister::Psp => PSP,
+        };
+        Ok(self.session.core(0)?.read_core_reg::<u32>(id)?)
+    }
+
+    fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), RunnerError> {
+        self.session.core(0)?.read_8(address as u64, buffer)?;
+        Ok(())
+    }
+}
+
+/// Fault state of cores without fault status registers, made of the stacked exception frame.
+fn stacked_fault_status(core: &mut Core, lr: u32, probe_serial: &ProbeSerial) -> FaultStatus {
+    FaultStatus::Armv6m {
+        stacked: read_exception_frame(core, lr)
+            .map_err(|e| {
+                warn!(
+                    "{}: Unable to read the exception frame: {}",
+                    probe_serial, e
+                )
+            })
+            .ok(),
+    }
+}
+
+/// Whether an ARMv8-M core implements the Main Extension, Mainline cores (Cortex-M33, M55) do
+/// while Baseline ones (Cortex-M23) do not.
+fn has_main_extension(core: &mut Core) -> Result<bool, RunnerError> {
+    let cpuid = core.read_word_32(CPUID as u64)?;
+    Ok((cpuid >> 16) & 0xf == CPUID_ARCHITECTURE_MAINLINE)
+}
+
+/// Read the fault status registers of ARMv7-M and ARMv8-M Mainline cores.
+///
+/// Returns HFSR, CFSR, MMFAR and BFAR, the address registers only when valid.
+fn read_fault_status(core: &mut Core) -> Result<(u32, u32, Option<u32>, Option<u32>), RunnerError> {
+    let hfsr = core.read_word_32(HFSR as u64)?;
+    let cfsr = core.read_word_32(CFSR as u64)?;
+    let mmfar = if cfsr & CFSR_MMARVALID != 0 {
+        Some(core.read_word_32(MMFAR as u64)?)
+    } else {
+        None
+    };
+    let bfar = if cfsr & CFSR_BFARVALID != 0 {
+        Some(core.read_word_32(BFAR as u64)?)
+    } else {
+        None
+    };
+    Ok((hfsr, cfsr, mmfar, bfar))
+}
+
+/// Read the exception frame the hardware stacked when entering the HardFault handler.
+fn read_exception_frame(core: &mut Core, exc_return: u32) -> Result<ExceptionFrame, RunnerError> {
+    let sp = if exc_return & EXC_RETURN_PROCESS_STACK != 0 {
+        core.read_core_reg::<u32>(PSP)?
+    } else {
+        core.read_core_reg::<u32>(SP)?
+    };
+    let mut words = [0u32; 8];
+    core.read_32(sp as u64, &mut words)?;
+    let [r0, r1, r2, r3, r12, lr, pc, xpsr] = words;
+    Ok(ExceptionFrame {
+        r0,
+        r1,
+        r2,
+        r3,
+        r12,
+        lr,
+        pc,
+        xpsr,
+    })
+}
@@ -0,0 +1,241 @@
+//! Virtual targets emulated by QEMU.
+
+use super::{
+    Backend, Connection, CoreRegister, Firmware, FlashReport, Halt, RttChannelInfo, RttChannels,
+};
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::{ProbeSerial, TargetName, Uuid};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Configuration of a virtual probe, running the firmware under QEMU instead of on a board.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QemuConfig {
+    /// Machine to emulate, e.g. `lm3s6965evb` or `mps2-an385`.
+    pub machine: String,
+    /// CPU to emulate, defaults to the machine's CPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    /// QEMU executable.
+    #[serde(default = "default_qemu_binary")]
+    pub binary: PathBuf,
+}
+
+fn default_qemu_binary() -> PathBuf {
+    "qemu-system-arm".into()
+}
+
+/// Backend running the firmware under `qemu-system-arm`.
+///
+/// The firmware's UART and semihosting output is captured in place of RTT, input for the RTT
+/// down channel goes to the UART instead. The run ends when
+/// QEMU exits, which firmware does through the semihosting `SYS_EXIT` call; the exit code of
+/// QEMU is the exit code of the run.
+pub struct QemuBackend {
+    configs: HashMap<ProbeSerial, QemuConfig>,
+}
+
+impl QemuBackend {
+    /// Create a backend for the virtual probes with the given configs.
+    pub fn new(configs: HashMap<ProbeSerial, QemuConfig>) -> Self {
+        Self { configs }
+    }
+}
+
+impl Backend for QemuBackend {
+    fn attach(
+        &self,
+        _target_name: &TargetName,
+        probe_serial: &ProbeSerial,
+        _probe_speed_khz: Option<u32>,
+    ) -> Result<Box<dyn Connection>, RunnerError> {
+        let config = self
+            .configs
+            .get(probe_serial)
+            .ok_or(anyhow!("{} is not a virtual probe", probe_serial))?;
+        Ok(Box::new(QemuConnection {
+            probe_serial: probe_serial.clone(),
+            config: config.clone(),
+            elf_path: std::env::temp_dir().join(format!("embedded-ci-{}.elf", Uuid::new_v4())),
+            child: None,
+            input: None,
+            output: Default::default(),
+            readers: Vec::new(),
+        }))
+    }
+}
+
+/// A firmware loaded into QEMU.
+struct QemuConnection {
+    probe_serial: ProbeSerial,
+    config: QemuConfig,
+    elf_path: PathBuf,
+    child: Option<Child>,
+    input: Option<ChildStdin>,
+    output: Arc<Mutex<Vec<u8>>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl QemuConnection {
+    /// Forward everything QEMU writes to `source` into the captured output.
+    fn capture(&mut self, mut source: impl Read + Send + 'static) {
+        let output = self.output.clone();
+        self.readers.push(thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            while let Ok(count) = source.read(&mut buffer) {
+                if count == 0 {
+                    break;
+                }
+                output.lock().unwrap().extend_from_slice(&buffer[..count]);
+            }
+        }));
+    }
+}
+
+impl Connection for QemuConnection {
+    fn flash(
+        &mut self,
+        firmware: &Firmware,
+        _cancel_flag: &AtomicBool,
+    ) -> Result<Option<FlashReport>, RunnerError> {
+        if firmware.images.len() > 1 {
+            return Err(anyhow!("QEMU targets only run a single image"))?;
+        }
+        if !firmware.primary.format.is_elf() {
+            return Err(anyhow!("QEMU targets only run ELF files"))?;
+        }
+        fs::write(&self.elf_path, &firmware.primary.binary)
+            .map_err(|e| anyhow!("Unable to store the ELF for QEMU: {}", e))?;
+        Ok(None)
+    }
+
+    fn prepare(&mut self, firmware: &Firmware) -> Result<(), RunnerError> {
+        if !firmware.breakpoints.is_empty() {
+            return Err(anyhow!("Breakpoints are not supported on QEMU targets"))?;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), RunnerError> {
+        let mut command = Command::new(&self.config.binary);
+        command.args(["-machine", &self.config.machine]);
+        if let Some(cpu) = &self.config.cpu {
+            command.args(["-cpu", cpu]);
+        }
+        command
+            .args([
+                "-nographic",
+                "-monitor",
+                "none",
+                "-serial",
+                "stdio",
+                "-semihosting-config",
+                "enable=on,target=native",
+                "-kernel",
+            ])
+            .arg(&self.elf_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        debug!("{}: Starting {:?}", self.probe_serial, command);
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!("Unable to start '{}': {}", self.config.binary.display(), e))?;
+        self.input = child.stdin.take();
+        if let Some(stdout) = child.stdout.take() {
+            self.capture(stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.capture(stderr);
+        }
+        self.child = Some(child);
+
+        Ok(())
+    }
+
+    /// The captured output and the serial input stand in for a single unnamed up and down
+    /// channel.
+    fn attach_rtt(&mut self, _firmware: &Firmware) -> Result<RttChannels, RunnerError> {
+        let channel = RttChannelInfo {
+            number: 0,
+            name: None,
+        };
+        Ok(RttChannels {
+            up: vec![channel.clone()],
+            down: vec![channel],
+        })
+    }
+
+    fn read_rtt(&mut self, _channel: usize, buffer: &mut [u8]) -> Result<usize, RunnerError> {
+        let mut output = self.output.lock().unwrap();
+        let count = output.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&output[..count]);
+        output.drain(..count);
+        Ok(count)
+    }
+
+    fn write_rtt(&mut self, _channel: usize, bytes: &[u8]) -> Result<usize, RunnerError> {
+        let input = self.input.as_mut().ok_or(anyhow!("QEMU is not running"))?;
+        input
+            .write_all(bytes)
+            .and_then(|_| input.flush())
+            .map_err(|e| anyhow!("Unable to write to QEMU: {}", e))?;
+        Ok(bytes.len())
+    }
+
+    fn halt_status(&mut self) -> Result<Option<Halt>, RunnerError> {
+        let child = self.child.as_mut().ok_or(anyhow!("QEMU is not running"))?;
+        let status = child
+            .try_wait()
+            .map_err(|e| anyhow!("Unable to query QEMU: {}", e))?;
+        if status.is_some() {
+            // Make sure all the output is captured before the runner reads it for the last time
+            for reader in self.readers.drain(..) {
+                let _ = reader.join();
+            }
+        }
+        // QEMU exits with the code passed to `SYS_EXIT_EXTENDED`, or 1 for a failed `SYS_EXIT`
+        Ok(status.map(|status| match status.code() {
+            Some(code) => Halt::Exit(code),
+            None => Halt::Other(format!("QEMU exited with {}", status)),
+        }))
+    }
+
+    fn halt(&mut self) -> Result<(), RunnerError> {
+        if let Some(child) = self.child.as_mut() {
+            child
+                .kill()
+                .map_err(|e| anyhow!("Unable to stop QEMU: {}", e))?;
+            let _ = child.wait();
+        }
+        Ok(())
+    }
+
+    fn read_core_register(&mut self, _register: CoreRegister) -> Result<u32, RunnerError> {
+        Err(anyhow!("Core registers of virtual targets cannot be read"))?
+    }
+
+    fn read_memory(&mut self, _address: u32, _buffer: &mut [u8]) -> Result<(), RunnerError> {
+        Err(anyhow!("Memory of virtual targets cannot be read"))?
+    }
+}
+
+impl Drop for QemuConnection {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_file(&self.elf_path);
+    }
+}
@@ -0,0 +1,315 @@
+//! Scripted simulated targets, used for testing the server without any hardware.
+
+use super::{
+    Backend, Connection, CoreRegister, Firmware, FlashReport, Halt, HardFault, RttChannelInfo,
+    RttChannels,
+};
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::job::ExecutionMode;
+use embedded_ci_common::{ProbeSerial, TargetName};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the sectors of the simulated flash.
+pub const SECTOR_SIZE: u64 = 1024;
+
+/// Where the core halts when the script does not set the program counter, holds `bkpt 0`.
+pub const EXIT_ADDRESS: u32 = 0xffff_0000;
+
+/// A step of what a simulated target does once it is started.
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Emit the bytes over RTT up channel 0.
+    Rtt(Vec<u8>),
+    /// Emit the bytes over the given RTT up channel.
+    RttOn(usize, Vec<u8>),
+    /// Wait until the bytes have been received over RTT down channel 0.
+    AwaitInput(Vec<u8>),
+    /// Keep running for the given time before moving on to the next step.
+    Delay(Duration),
+    /// Halt on a breakpoint.
+    Breakpoint,
+    /// Halt in the HardFault handler.
+    HardFault(HardFault),
+    /// Keep running forever.
+    Hang,
+}
+
+/// Scripted behaviour of a simulated target.
+///
+/// A target that runs out of steps keeps running forever.
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    /// Fail when the runner tries to attach.
+    pub fail_attach: bool,
+    /// Fail when the runner tries to flash.
+    pub fail_flash: bool,
+    /// Time it takes to flash each image.
+    pub flash_time: Duration,
+    /// Steps executed once the target is started.
+    pub steps: Vec<Step>,
+    /// Names of the RTT up channels, a single unnamed channel when empty.
+    pub up_channels: Vec<Option<String>>,
+    /// Names of the RTT down channels, a single unnamed channel when empty.
+    pub down_channels: Vec<Option<String>>,
+    /// Core registers, readable once the target has halted.
+    ///
+    /// Without a program counter the core halts on a `bkpt 0` at [`EXIT_ADDRESS`], as a firmware
+    /// exiting successfully does.
+    pub registers: HashMap<CoreRegister, u32>,
+    /// Memory regions, as start address and contents.
+    pub memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl Script {
+    /// Script emitting `rtt` and then halting on a breakpoint.
+    pub fn success(rtt: &[u8]) -> Self {
+        Self {
+            steps: vec![Step::Rtt(rtt.to_vec()), Step::Breakpoint],
+            ..Default::default()
+        }
+    }
+}
+
+/// Backend simulating a scripted target behind each of the probe serials.
+#[derive(Default)]
+pub struct SimulatedBackend {
+    scripts: HashMap<ProbeSerial, Script>,
+}
+
+impl SimulatedBackend {
+    /// Create a backend without any targets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a simulated target behind the probe with `probe_serial`.
+    pub fn with_target(mut self, probe_serial: &str, script: Script) -> Self {
+        self.scripts
+            .insert(ProbeSerial(probe_serial.into()), script);
+        self
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn attach(
+        &self,
+        _target_name: &TargetName,
+        probe_serial: &ProbeSerial,
+        _probe_speed_khz: Option<u32>,
+    ) -> Result<Box<dyn Connection>, RunnerError> {
+        let script = self
+            .scripts
+            .get(probe_serial)
+            .ok_or(anyhow!("No simulated target behind probe {}", probe_serial))?;
+        if script.fail_attach {
+            return Err(anyhow!("Simulated attach failure"))?;
+        }
+        let mut registers = script.registers.clone();
+        let mut memory = script.memory.clone();
+        if let Entry::Vacant(pc) = registers.entry(CoreRegister::R(15)) {
+            pc.insert(EXIT_ADDRESS);
+            memory.push((EXIT_ADDRESS, vec![0x00, 0xbe]));
+        }
+        Ok(Box::new(SimulatedConnection {
+            fail_flash: script.fail_flash,
+            flash_time: script.flash_time,
+            steps: script.steps.iter().cloned().collect(),
+            running: false,
+            delay_until: None,
+            up_channels: channels_or_default(&script.up_channels),
+            down_channels: channels_or_default(&script.down_channels),
+            rtt: HashMap::new(),
+            input: Vec::new(),
+            halt: None,
+            registers,
+            memory,
+        }))
+    }
+}
+
+fn channels_or_default(names: &[Option<String>]) -> Vec<Option<String>> {
+    match names.is_empty() {
+        true => vec![None],
+        false => names.to_vec(),
+    }
+}
+
+/// An attached simulated target.
+struct SimulatedConnection {
+    fail_flash: bool,
+    flash_time: Duration,
+    steps: VecDeque<Step>,
+    running: bool,
+    delay_until: Option<Instant>,
+    up_channels: Vec<Option<String>>,
+    down_channels: Vec<Option<String>>,
+    /// Bytes emitted but not read yet, per up channel.
+    rtt: HashMap<usize, Vec<u8>>,
+    /// Bytes received over down channel 0 and not consumed by a step yet.
+    input: Vec<u8>,
+    halt: Option<Halt>,
+    registers: HashMap<CoreRegister, u32>,
+    memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl SimulatedConnection {
+    /// Execute the steps which are due.
+    fn advance(&mut self) {
+        if !self.running {
+            return;
+        }
+        while let Some(step) = self.steps.front() {
+            match step {
+                Step::Rtt(bytes) => self.rtt.entry(0).or_default().extend_from_slice(bytes),
+                Step::RttOn(channel, bytes) => self
+                    .rtt
+                    .entry(*channel)
+                    .or_default()
+                    .extend_from_slice(bytes),
+                Step::AwaitInput(expected) => {
+                    let found = self
+                        .input
+                        .windows(expected.len())
+                        .position(|window| window == &expected[..]);
+                    match found {
+                        Some(position) => {
+                            self.input.drain(..position + expected.len());
+                        }
+                        None => return,
+                    }
+                }
+                Step::Delay(delay) => {
+                    let until = *self.delay_until.get_or_insert(Instant::now() + *delay);
+                    if Instant::now() < until {
+                        return;
+                    }
+                    self.delay_until = None;
+                }
+                Step::Breakpoint => self.stop(Halt::Breakpoint),
+                Step::HardFault(fault) => self.stop(Halt::HardFault(fault.clone())),
+                Step::Hang => return,
+            }
+            self.steps.pop_front();
+            if !self.running {
+                return;
+            }
+        }
+    }
+
+    fn stop(&mut self, halt: Halt) {
+        self.running = false;
+        self.halt = Some(halt);
+    }
+}
+
+impl Connection for SimulatedConnection {
+    fn flash(
+        &mut self,
+        firmware: &Firmware,
+        cancel_flag: &AtomicBool,
+    ) -> Result<Option<FlashReport>, RunnerError> {
+        if self.fail_flash {
+            return Err(anyhow!("Simulated flash failure"))?;
+        }
+        for _ in firmware.images {
+            if cancel_flag.load(Ordering::Relaxed) {
+                return Err(RunnerError::Cancelled(Vec::new()));
+            }
+            thread::sleep(self.flash_time);
+        }
+        if firmware.execution_mode == ExecutionMode::Ram {
+            return Ok(None);
+        }
+        let sizes = firmware
+            .info
+            .iter()
+            .flat_map(|image| image.sections.iter())
+            .filter(|section| section.load_range.is_some())
+            .map(|section| section.data.len() as u64);
+        // The simulated flash is made of sectors of `SECTOR_SIZE`, each section erasing its own
+        Ok(Some(sizes.fold(FlashReport::default(), |report, size| {
+            FlashReport {
+                bytes_programmed: report.bytes_programmed + size,
+                sectors_erased: report.sectors_erased + size.div_ceil(SECTOR_SIZE),
+            }
+        })))
+    }
+
+    fn prepare(&mut self, _firmware: &Firmware) -> Result<(), RunnerError> {
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), RunnerError> {
+        self.running = true;
+        Ok(())
+    }
+
+    fn attach_rtt(&mut self, _firmware: &Firmware) -> Result<RttChannels, RunnerError> {
+        let infos = |names: &[Option<String>]| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(number, name)| RttChannelInfo {
+                    number,
+                    name: name.clone(),
+                })
+                .collect()
+        };
+        Ok(RttChannels {
+            up: infos(&self.up_channels),
+            down: infos(&self.down_channels),
+        })
+    }
+
+    fn read_rtt(&mut self, channel: usize, buffer: &mut [u8]) -> Result<usize, RunnerError> {
+        self.advance();
+        let rtt = self.rtt.entry(channel).or_default();
+        let count = rtt.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&rtt[..count]);
+        rtt.drain(..count);
+        Ok(count)
+    }
+
+    fn write_rtt(&mut self, channel: usize, bytes: &[u8]) -> Result<usize, RunnerError> {
+        if channel == 0 {
+            self.input.extend_from_slice(bytes);
+        }
+        Ok(bytes.len())
+    }
+
+    fn halt_status(&mut self) -> Result<Option<Halt>, RunnerError> {
+        self.advance();
+        Ok(self.halt.clone())
+    }
+
+    fn halt(&mut self) -> Result<(), RunnerError> {
+        if self.running {
+            self.stop(Halt::Other("halted by the runner".into()));
+        }
+        Ok(())
+    }
+
+    fn read_core_register(&mut self, register: CoreRegister) -> Result<u32, RunnerError> {
+        Ok(*self
+            .registers
+            .get(&register)
+            .ok_or(anyhow!("Simulated register {:?} is not set", register))?)
+    }
+
+    fn read_memory(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), RunnerError> {
+        let (start, data) = self
+            .memory
+            .iter()
+            .find(|(start, data)| {
+                address >= *start && (address - start) as usize + buffer.len() <= data.len()
+            })
+            .ok_or(anyhow!("Simulated memory at {:#010x} is not set", address))?;
+        let offset = (address - start) as usize;
+        buffer.copy_from_slice(&data[offset..offset + buffer.len()]);
+        Ok(())
+    }
+}
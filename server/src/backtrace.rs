@@ -0,0 +1,532 @@
+//! Unwinding of a faulted firmware's stack into a symbolicated backtrace.
+//!
+//! Call frames are unwound with the CFI found in the ELF's `.debug_frame`. Exception frames
+//! stacked by the hardware are walked through using the `EXC_RETURN` value found in `LR`.
+
+use crate::backend::{Connection, CoreRegister};
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::job::BacktraceFrame;
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EndianSlice, LittleEndian, RegisterRule, UnwindContext,
+    UnwindSection, UnwindTableRow,
+};
+use object::{File, Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::borrow::Cow;
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+type Registers = [Option<u32>; 16];
+
+/// Upper bound of frames, protects against corrupted stacks.
+const MAX_FRAMES: usize = 64;
+const THUMB_BIT: u32 = 1;
+const SP: usize = 13;
+const LR: usize = 14;
+const PC: usize = 15;
+/// Bits set in all the `EXC_RETURN` values.
+const EXC_RETURN_MARKER: u32 = 0xffff_ff00;
+/// Set in `EXC_RETURN` when the exception frame was pushed to the process stack.
+const EXC_RETURN_PROCESS_STACK: u32 = 1 << 2;
+/// Set in `EXC_RETURN` when the exception frame does not hold the FPU state.
+const EXC_RETURN_BASIC_FRAME: u32 = 1 << 4;
+/// Value of `LR` out of reset, marks the bottom of the stack.
+const LR_AT_RESET: u32 = 0xffff_ffff;
+/// Set in the stacked xPSR when the hardware padded the stack for alignment.
+const XPSR_STACK_ALIGNED: u32 = 1 << 9;
+const BASIC_FRAME_SIZE: u32 = 0x20;
+const FPU_STATE_SIZE: u32 = 0x48;
+
+/// Unwind the stack of the halted core behind `connection`.
+///
+/// Returns the frames innermost first, inlined functions get a frame of their own.
+pub fn backtrace(
+    elf_bytes: &[u8],
+    connection: &mut dyn Connection,
+) -> Result<Vec<BacktraceFrame>, RunnerError> {
+    let elf = File::parse(elf_bytes).map_err(|e| anyhow!("ELF parsing error: {}", e))?;
+    let symbolizer = Symbolizer::new(&elf)?;
+    let mut debug_frame = DebugFrame::new(section_data(&elf, ".debug_frame"), LittleEndian);
+    debug_frame.set_address_size(4);
+    let bases = BaseAddresses::default();
+    let mut context = UnwindContext::new();
+
+    let mut registers = [None; 16];
+    for (n, register) in registers.iter_mut().enumerate() {
+        *register = Some(connection.read_core_register(CoreRegister::R(n as u16))?);
+    }
+
+    let mut frames = Vec::new();
+    // Return addresses point after the call, look up the call itself instead
+    let mut is_return_address = false;
+    while frames.len() < MAX_FRAMES {
+        let pc = match registers[PC] {
+            Some(pc) => pc & !THUMB_BIT,
+            None => break,
+        };
+        let lookup = if is_return_address {
+            pc.saturating_sub(1)
+        } else {
+            pc
+        };
+        frames.extend(symbolizer.frames(pc, lookup));
+        // Past a corrupted exception frame the stack pointer is unknown
+        if registers[SP].is_none() {
+            break;
+        }
+
+        let caller = match debug_frame.unwind_info_for_address(
+            &bases,
+            &mut context,
+            lookup as u64,
+            DebugFrame::cie_from_offset,
+        ) {
+            Ok(row) => unwind_call_frame(row, &registers, connection)?,
+            // Without CFI only the entry of an exception handler can be unwound, there the stack
+            // pointer and the link register are still untouched
+            Err(_) if registers[LR].is_some_and(is_exc_return) => {
+                let mut caller = registers;
+                caller[PC] = registers[LR];
+                caller
+            }
+            Err(_) => break,
+        };
+
+        match caller[PC] {
+            None | Some(LR_AT_RESET) => break,
+            Some(exc_return) if is_exc_return(exc_return) => {
+                registers = unwind_exception_frame(exc_return, caller, connection)?;
+                is_return_address = false;
+            }
+            Some(return_address) => {
+                if caller[SP] == registers[SP] && return_address & !THUMB_BIT == pc {
+                    // No progress, the CFI does not match the stack
+                    break;
+                }
+                registers = caller;
+                is_return_address = true;
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+fn is_exc_return(value: u32) -> bool {
+    value & EXC_RETURN_MARKER == EXC_RETURN_MARKER
+}
+
+/// Registers of the caller, with its return address in place of its `PC`.
+fn unwind_call_frame(
+    row: &UnwindTableRow<Reader>,
+    registers: &Registers,
+    connection: &mut dyn Connection,
+) -> Result<Registers, RunnerError> {
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => registers
+            .get(register.0 as usize)
+            .copied()
+            .flatten()
+            .map(|value| (value as i64 + offset) as u32)
+            .ok_or(anyhow!("CFA register {} is unknown", register.0))?,
+        CfaRule::Expression(_) => Err(anyhow!("CFA expressions are not supported"))?,
+    };
+
+    let mut caller = *registers;
+    for (n, register) in caller.iter_mut().enumerate() {
+        *register = match row.register(gimli::Register(n as u16)) {
+            // Registers without a rule, such as `LR` in leaf functions, keep their value
+            RegisterRule::Undefined | RegisterRule::SameValue => registers[n],
+            RegisterRule::Offset(offset) => {
+                Some(read_word(connection, (cfa as i64 + offset) as u32)?)
+            }
+            RegisterRule::ValOffset(offset) => Some((cfa as i64 + offset) as u32),
+            RegisterRule::Register(other) => registers.get(other.0 as usize).copied().flatten(),
+            _ => None,
+        };
+    }
+    caller[SP] = Some(cfa);
+    caller[PC] = caller[LR];
+
+    Ok(caller)
+}
+
+/// Registers of the code interrupted by the exception, as stacked by the hardware.
+///
+/// The stack pointer is left unknown when a corrupted one puts the frame past the address space.
+fn unwind_exception_frame(
+    exc_return: u32,
+    registers: Registers,
+    connection: &mut dyn Connection,
+) -> Result<Registers, RunnerError> {
+    let sp = if exc_return & EXC_RETURN_PROCESS_STACK != 0 {
+        connection.read_core_register(CoreRegister::Psp)?
+    } else {
+        registers[SP].ok_or(anyhow!("Stack pointer is unknown"))?
+    };
+
+    let mut frame = [0u8; BASIC_FRAME_SIZE as usize];
+    connection.read_memory(sp, &mut frame)?;
+    let stacked: Vec<u32> = frame
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+
+    let mut interrupted = registers;
+    for (n, value) in [0, 1, 2, 3, 12, LR, PC].into_iter().zip(stacked.iter()) {
+        interrupted[n] = Some(*value);
+    }
+    let xpsr = stacked[7];
+    let mut frame_size = BASIC_FRAME_SIZE;
+    if exc_return & EXC_RETURN_BASIC_FRAME == 0 {
+        frame_size += FPU_STATE_SIZE;
+    }
+    if xpsr & XPSR_STACK_ALIGNED != 0 {
+        frame_size += 4;
+    }
+    interrupted[SP] = sp.checked_add(frame_size);
+
+    Ok(interrupted)
+}
+
+fn read_word(connection: &mut dyn Connection, address: u32) -> Result<u32, RunnerError> {
+    let mut word = [0u8; 4];
+    connection.read_memory(address, &mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn section_data<'a>(elf: &File<'a>, name: &str) -> &'a [u8] {
+    elf.section_by_name(name)
+        .and_then(|section| section.data().ok())
+        .unwrap_or(&[])
+}
+
+/// Maps addresses to functions and source locations.
+struct Symbolizer<'a> {
+    context: addr2line::Context<Reader<'a>>,
+    /// Function symbols as start address, size and name, sorted by address.
+    symbols: Vec<(u32, u32, &'a str)>,
+}
+
+impl<'a> Symbolizer<'a> {
+    fn new(elf: &File<'a>) -> Result<Self, RunnerError> {
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader<'a>, gimli::Error> {
+            Ok(EndianSlice::new(section_data(elf, id.name()), LittleEndian))
+        })
+        .map_err(|e| anyhow!("Unable to load the debug information: {}", e))?;
+        let context = addr2line::Context::from_dwarf(dwarf)
+            .map_err(|e| anyhow!("Unable to parse the debug information: {}", e))?;
+
+        let mut symbols: Vec<_> = elf
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text)
+            .filter_map(|symbol| {
+                Some((
+                    symbol.address() as u32 & !THUMB_BIT,
+                    symbol.size() as u32,
+                    symbol.name().ok()?,
+                ))
+            })
+            .collect();
+        symbols.sort_by_key(|(start, _, _)| *start);
+
+        Ok(Self { context, symbols })
+    }
+
+    /// Frames at `pc`, one per inlined function, innermost first.
+    fn frames(&self, pc: u32, lookup: u32) -> Vec<BacktraceFrame> {
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = self.context.find_frames(lookup as u64).skip_all_loads() {
+            while let Ok(Some(frame)) = iter.next() {
+                frames.push(BacktraceFrame {
+                    address: pc,
+                    function: frame
+                        .function
+                        .as_ref()
+                        .and_then(|function| function.demangle().ok())
+                        .map(Cow::into_owned),
+                    file: frame
+                        .location
+                        .as_ref()
+                        .and_then(|location| location.file)
+                        .map(String::from),
+                    line: frame.location.as_ref().and_then(|location| location.line),
+                });
+            }
+        }
+
+        // Fall back on the symbol table when there is no debug information
+        match frames.last_mut() {
+            Some(frame) if frame.function.is_some() => {}
+            Some(frame) => frame.function = self.symbol(lookup),
+            None => frames.push(BacktraceFrame {
+                address: pc,
+                function: self.symbol(lookup),
+                ..Default::default()
+            }),
+        }
+        frames
+    }
+
+    /// Name of the function symbol covering `address`.
+    fn symbol(&self, address: u32) -> Option<String> {
+        let (start, size, name) = self
+            .symbols
+            .iter()
+            .rev()
+            .find(|(start, _, _)| *start <= address)?;
+        if *size != 0 && address - start >= *size {
+            return None;
+        }
+        Some(addr2line::demangle_auto(Cow::from(*name), None).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::simulated::{Script, SimulatedBackend};
+    use crate::backend::Backend;
+    use embedded_ci_common::{ProbeSerial, TargetName};
+    use gimli::write::{
+        self, Address, AttributeValue, CallFrameInstruction, CommonInformationEntry, DwarfUnit,
+        EndianVec, FrameDescriptionEntry, FrameTable, LineProgram, LineString, Sections,
+    };
+    use gimli::{Encoding, Format, LineEncoding, Register};
+    use std::collections::HashMap;
+
+    /// A function of the firmware built by [`debug_elf`].
+    struct Function {
+        name: &'static str,
+        start: u32,
+        size: u32,
+        /// Whether the prologue pushes `{r7, lr}`
+        pushes: bool,
+        /// Line of the instructions from each address on, no debug information when empty
+        lines: &'static [(u32, u64)],
+    }
+
+    const FUNCTIONS: [Function; 4] = [
+        Function {
+            name: "main",
+            start: 0x00,
+            size: 0x10,
+            pushes: true,
+            lines: &[(0x00, 10), (0x04, 11), (0x08, 12)],
+        },
+        Function {
+            name: "inner",
+            start: 0x10,
+            size: 0x10,
+            pushes: true,
+            lines: &[(0x10, 20), (0x14, 21), (0x18, 22)],
+        },
+        Function {
+            name: "leaf",
+            start: 0x20,
+            size: 0x08,
+            pushes: false,
+            lines: &[(0x20, 30), (0x22, 31)],
+        },
+        Function {
+            name: "HardFault",
+            start: 0x28,
+            size: 0x08,
+            pushes: false,
+            lines: &[],
+        },
+    ];
+    const STACK: u32 = 0x2000_0100;
+
+    /// An ELF holding the CFI and the line information of the [`FUNCTIONS`] in `src/main.rs`.
+    fn debug_elf() -> Vec<u8> {
+        use object::write::{Object, Symbol, SymbolSection};
+        use object::{
+            Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind,
+            SymbolScope,
+        };
+
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: 4,
+        };
+        let mut dwarf = DwarfUnit::new(encoding);
+        dwarf.unit.line_program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::String(b"/firmware".to_vec()),
+            LineString::String(b"src/main.rs".to_vec()),
+            None,
+        );
+        let directory = dwarf.unit.line_program.default_directory();
+        let file = dwarf.unit.line_program.add_file(
+            LineString::String(b"src/main.rs".to_vec()),
+            directory,
+            None,
+        );
+        let root = dwarf.unit.root();
+        let entry = dwarf.unit.get_mut(root);
+        entry.set(
+            gimli::DW_AT_name,
+            AttributeValue::String(b"src/main.rs".to_vec()),
+        );
+        entry.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(0)),
+        );
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(0x28));
+
+        let mut cie = CommonInformationEntry::new(encoding, 2, -4, Register(LR as u16));
+        cie.add_instruction(CallFrameInstruction::Cfa(Register(SP as u16), 0));
+        let mut frame_table = FrameTable::default();
+        let cie = frame_table.add_cie(cie);
+
+        for function in FUNCTIONS
+            .iter()
+            .filter(|function| !function.lines.is_empty())
+        {
+            let subprogram = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+            let entry = dwarf.unit.get_mut(subprogram);
+            entry.set(
+                gimli::DW_AT_name,
+                AttributeValue::String(function.name.into()),
+            );
+            let low_pc = Address::Constant(function.start.into());
+            entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(low_pc));
+            entry.set(
+                gimli::DW_AT_high_pc,
+                AttributeValue::Udata(function.size.into()),
+            );
+
+            let line_program = &mut dwarf.unit.line_program;
+            line_program.begin_sequence(Some(low_pc));
+            for (address, line) in function.lines.iter() {
+                let row = line_program.row();
+                row.address_offset = (address - function.start).into();
+                row.file = file;
+                row.line = *line;
+                line_program.generate_row();
+            }
+            line_program.end_sequence(function.size.into());
+
+            let mut fde = FrameDescriptionEntry::new(low_pc, function.size);
+            if function.pushes {
+                // push {r7, lr}
+                fde.add_instruction(2, CallFrameInstruction::CfaOffset(8));
+                fde.add_instruction(2, CallFrameInstruction::Offset(Register(7), -8));
+                fde.add_instruction(2, CallFrameInstruction::Offset(Register(LR as u16), -4));
+            }
+            frame_table.add_fde(cie, fde);
+        }
+
+        let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        let mut debug_frame = write::DebugFrame(EndianVec::new(gimli::LittleEndian));
+        frame_table.write_debug_frame(&mut debug_frame).unwrap();
+
+        let mut elf = Object::new(BinaryFormat::Elf, Architecture::Arm, Endianness::Little);
+        let text = elf.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+        elf.set_section_data(text, [0x00, 0xbe].repeat(0x18), 4);
+        let mut debug_sections = vec![(".debug_frame", debug_frame.0.into_vec())];
+        sections
+            .for_each(|id, data| -> Result<(), ()> {
+                debug_sections.push((id.name(), data.clone().into_vec()));
+                Ok(())
+            })
+            .unwrap();
+        for (name, data) in debug_sections {
+            if !data.is_empty() {
+                let section = elf.add_section(Vec::new(), name.into(), SectionKind::Debug);
+                elf.set_section_data(section, data, 1);
+            }
+        }
+        for function in FUNCTIONS.iter() {
+            elf.add_symbol(Symbol {
+                name: function.name.into(),
+                value: (function.start | THUMB_BIT).into(),
+                size: function.size.into(),
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Linkage,
+                weak: false,
+                section: SymbolSection::Section(text),
+                flags: SymbolFlags::None,
+            });
+        }
+        elf.write().unwrap()
+    }
+
+    fn backtrace_of(
+        registers: HashMap<CoreRegister, u32>,
+        memory: Vec<(u32, Vec<u8>)>,
+    ) -> Vec<BacktraceFrame> {
+        let backend = SimulatedBackend::new().with_target(
+            "PROBE_SERIAL_1",
+            Script {
+                registers,
+                memory,
+                ..Default::default()
+            },
+        );
+        let mut connection = backend
+            .attach(
+                &TargetName("TARGET".into()),
+                &ProbeSerial("PROBE_SERIAL_1".into()),
+                None,
+            )
+            .unwrap();
+        backtrace(&debug_elf(), connection.as_mut()).unwrap()
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn frame(address: u32, function: &str, line: Option<u32>) -> BacktraceFrame {
+        BacktraceFrame {
+            address,
+            function: Some(function.into()),
+            file: line.map(|_| "src/main.rs".into()),
+            line,
+        }
+    }
+
+    #[test]
+    fn call_frames_are_unwound_with_the_cfi() {
+        // Halted in `leaf`, called by `inner` which was called by `main`
+        let mut registers: HashMap<_, _> = (0..16).map(|n| (CoreRegister::R(n), 0)).collect();
+        registers.insert(CoreRegister::R(SP as u16), STACK);
+        registers.insert(CoreRegister::R(LR as u16), 0x18 | THUMB_BIT);
+        registers.insert(CoreRegister::R(PC as u16), 0x22);
+        // The `{r7, lr}` pushed by `inner` and then by `main`
+        let stack = words(&[0, 0x08 | THUMB_BIT, 0, LR_AT_RESET]);
+
+        assert_eq!(
+            backtrace_of(registers, vec![(STACK, stack)]),
+            &[
+                frame(0x22, "leaf", Some(31)),
+                frame(0x18, "inner", Some(21)),
+                frame(0x08, "main", Some(11)),
+            ]
+        );
+    }
+
+    #[test]
+    fn corrupted_exception_frames_stop_the_unwinding() {
+        // Entered `HardFault` from `leaf`, the stacked frame is padded for alignment but sits
+        // at the very end of the address space
+        let stack = u32::MAX - BASIC_FRAME_SIZE + 1;
+        let mut registers: HashMap<_, _> = (0..16).map(|n| (CoreRegister::R(n), 0)).collect();
+        registers.insert(CoreRegister::R(SP as u16), stack);
+        registers.insert(CoreRegister::R(LR as u16), 0xffff_fff9);
+        registers.insert(CoreRegister::R(PC as u16), 0x28);
+        let frame_words = [0, 0, 0, 0, 0, 0x18 | THUMB_BIT, 0x22, XPSR_STACK_ALIGNED];
+
+        assert_eq!(
+            backtrace_of(registers, vec![(stack, words(&frame_words))]),
+            &[
+                frame(0x28, "HardFault", None),
+                frame(0x22, "leaf", Some(31))
+            ]
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use crate::backend::QemuConfig;
 use anyhow::anyhow;
 use clap::Parser;
 use embedded_ci_common::{
-    AuthName, AuthToken, ProbeAlias, ProbeSerial, TargetGroup, TargetName, Target, Targets,
+    AuthName, AuthToken, MemoryKind, MemoryRegion, ProbeAlias, ProbeSerial, Target, TargetGroup,
+    TargetName, Targets,
 };
 use log::*;
+use probe_rs::config::MemoryRegion as ProbeRsMemoryRegion;
 use probe_rs::Probe;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
@@ -33,6 +36,9 @@ pub struct ProbeInfo {
     pub groups: Vec<TargetGroup>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub probe_speed_khz: Option<u32>,
+    /// Makes this a virtual probe, running the firmware under QEMU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qemu: Option<QemuConfig>,
 }
 
 pub struct Cli {
@@ -49,12 +55,17 @@ pub fn from_cli(target_settings: &HashMap<ProbeSerial, ProbeInfo>) -> anyhow::Re
             Some(serial_number) => {
                 let probe_serial = ProbeSerial(serial_number.clone());
                 match target_settings.get(&probe_serial) {
+                    Some(probe_info_settings) if probe_info_settings.qemu.is_some() => warn!(
+                        "Probe {} is configured as a virtual probe, ignoring the physical one",
+                        serial_number
+                    ),
                     Some(probe_info_settings) => {
                         targets.push(Target {
                             probe_serial,
                             probe_alias: probe_info_settings.probe_alias.clone(),
                             target_name: probe_info_settings.target_name.clone(),
                             groups: probe_info_settings.groups.clone().into(),
+                            memory_map: memory_map(&probe_info_settings.target_name),
                         })?;
                     }
                     None => warn!("Probe {} is not registered in a config file", serial_number),
@@ -66,9 +77,54 @@ pub fn from_cli(target_settings: &HashMap<ProbeSerial, ProbeInfo>) -> anyhow::Re
         }
     }
 
+    for (probe_serial, probe_info_settings) in target_settings {
+        if probe_info_settings.qemu.is_some() {
+            targets.push(Target {
+                probe_serial: probe_serial.clone(),
+                probe_alias: probe_info_settings.probe_alias.clone(),
+                target_name: probe_info_settings.target_name.clone(),
+                groups: probe_info_settings.groups.clone().into(),
+                // QEMU machines do not follow the memory maps of the chips
+                memory_map: Vec::new(),
+            })?;
+        }
+    }
+
     Ok(targets)
 }
 
+/// Memory map of the chip from the `probe-rs` target database, empty when it is not in there.
+fn memory_map(target_name: &TargetName) -> Vec<MemoryRegion> {
+    let target = match probe_rs::config::get_target_by_name(&target_name.0) {
+        Ok(target) => target,
+        Err(e) => {
+            warn!(
+                "Memory map of {} is unknown, the layout of its firmwares is not checked: {}",
+                target_name, e
+            );
+            return Vec::new();
+        }
+    };
+    target
+        .memory_map
+        .iter()
+        .map(|region| match region {
+            ProbeRsMemoryRegion::Nvm(region) => MemoryRegion {
+                kind: MemoryKind::Flash,
+                range: region.range.clone(),
+            },
+            ProbeRsMemoryRegion::Ram(region) => MemoryRegion {
+                kind: MemoryKind::Ram,
+                range: region.range.clone(),
+            },
+            ProbeRsMemoryRegion::Generic(region) => MemoryRegion {
+                kind: MemoryKind::Other,
+                range: region.range.clone(),
+            },
+        })
+        .collect()
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct SavedSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -96,7 +152,7 @@ impl std::fmt::Display for SavedSettings {
         for (serial, conf) in &self.probe_configs {
             writeln!(
                 f,
-                "    - {}: {{ target_name: {}, probe_alias: {}{} }}",
+                "    - {}: {{ target_name: {}, probe_alias: {}{}{} }}",
                 serial,
                 conf.target_name,
                 if conf.probe_alias.0.is_empty() {
@@ -108,6 +164,11 @@ impl std::fmt::Display for SavedSettings {
                     format!(", probe_speed_khz: {}", speed)
                 } else {
                     format!("")
+                },
+                if let Some(qemu) = &conf.qemu {
+                    format!(", qemu_machine: {}", qemu.machine)
+                } else {
+                    String::new()
                 }
             )?;
         }
@@ -124,6 +185,19 @@ impl std::fmt::Display for SavedSettings {
             "    - max_jobs_in_queue: {}",
             self.server_configs.max_jobs_in_queue.0
         )?;
+        writeln!(
+            f,
+            "    - job_store: {}",
+            self.server_configs.job_store.0.display()
+        )?;
+        writeln!(
+            f,
+            "    - target_stats: {}",
+            self.server_configs.target_stats.0.display()
+        )?;
+        if let Some(warning) = self.server_configs.flash_operations_warning {
+            writeln!(f, "    - flash_operations_warning: {}", warning)?;
+        }
 
         Ok(())
     }
@@ -135,6 +209,33 @@ pub struct ServerConfigs {
     pub max_target_timeout: Timeout,
     #[serde(default)]
     pub max_jobs_in_queue: MaxJobsInQueue,
+    #[serde(default)]
+    pub job_store: JobStorePath,
+    #[serde(default)]
+    pub target_stats: TargetStatsPath,
+    /// Flash operations after which a target is marked as due for replacement.
+    #[serde(default)]
+    pub flash_operations_warning: Option<u64>,
+}
+
+/// Path to the file persisting jobs and their results.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStorePath(pub PathBuf);
+
+impl Default for JobStorePath {
+    fn default() -> Self {
+        JobStorePath("job-store.jsonl".into())
+    }
+}
+
+/// Path to the file persisting the usage statistics of the targets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TargetStatsPath(pub PathBuf);
+
+impl Default for TargetStatsPath {
+    fn default() -> Self {
+        TargetStatsPath("target-stats.json".into())
+    }
 }
 
 /// Timeout in seconds.
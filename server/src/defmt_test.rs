@@ -0,0 +1,168 @@
+//! Per-test outcomes of `defmt-test` firmwares, recognised from the logs they print.
+//!
+//! `defmt-test` announces each test with "(1/3) running `name`...", optionally confirms it with
+//! "(1/3) `name` passed", reports ignored tests with "(2/3) ignoring `name`..." and ends with
+//! "all tests passed!". A panic ends the run in the middle of the test that was running.
+
+use embedded_ci_common::job::{LogRecord, TestCase, TestStatus};
+use std::time::Instant;
+
+/// Prefix of the message `defmt::panic!` and `panic-probe` log when panicking.
+const PANIC_PREFIX: &str = "panicked at";
+
+/// Follows the logs of a run and tracks the `defmt-test` tests in them.
+#[derive(Default)]
+pub struct TestTracker {
+    tests: Vec<TestCase>,
+    /// Test currently running and when it started.
+    running: Option<(String, Instant)>,
+    panic: Option<String>,
+}
+
+impl TestTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for a record logged by the firmware just now.
+    pub fn record(&mut self, record: &LogRecord) {
+        self.record_at(record, Instant::now())
+    }
+
+    fn record_at(&mut self, record: &LogRecord, now: Instant) {
+        let message = strip_counter(record.message.trim());
+        if let Some(name) = enclosed(message, "running `", "`...") {
+            self.end_running(TestStatus::Passed, now);
+            self.running = Some((name.into(), now));
+        } else if let Some(name) = enclosed(message, "`", "` passed") {
+            if matches!(&self.running, Some((running, _)) if running == name) {
+                self.end_running(TestStatus::Passed, now);
+            }
+        } else if let Some(name) = enclosed(message, "ignoring `", "`...") {
+            self.end_running(TestStatus::Passed, now);
+            self.tests.push(TestCase {
+                name: name.into(),
+                status: TestStatus::Ignored,
+                duration_ms: 0,
+                message: None,
+            });
+        } else if message == "all tests passed!" {
+            self.end_running(TestStatus::Passed, now);
+        } else if message.starts_with(PANIC_PREFIX) && self.running.is_some() {
+            self.panic.get_or_insert_with(|| message.into());
+        }
+    }
+
+    /// Outcomes of the tests seen so far, `passed` tells whether the run itself succeeded.
+    ///
+    /// A test still running at the end of a failed run is the one that brought it down.
+    pub fn finish(mut self, passed: bool) -> Vec<TestCase> {
+        let status = if passed && self.panic.is_none() {
+            TestStatus::Passed
+        } else {
+            TestStatus::Failed
+        };
+        self.end_running(status, Instant::now());
+        self.tests
+    }
+
+    fn end_running(&mut self, status: TestStatus, now: Instant) {
+        if let Some((name, start)) = self.running.take() {
+            self.tests.push(TestCase {
+                name,
+                status,
+                duration_ms: now.duration_since(start).as_millis() as u64,
+                message: self.panic.take(),
+            });
+        }
+    }
+}
+
+/// Strip the "(1/3) " counter in front of the messages.
+fn strip_counter(message: &str) -> &str {
+    message
+        .strip_prefix('(')
+        .and_then(|rest| rest.split_once(") "))
+        .filter(|(counter, _)| {
+            counter
+                .split_once('/')
+                .is_some_and(|(n, total)| is_number(n) && is_number(total))
+        })
+        .map_or(message, |(_, rest)| rest)
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn enclosed<'a>(message: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    message.strip_prefix(prefix)?.strip_suffix(suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn track(messages: &[&str], passed: bool) -> Vec<TestCase> {
+        let start = Instant::now();
+        let mut tracker = TestTracker::new();
+        for (n, message) in messages.iter().enumerate() {
+            tracker.record_at(
+                &LogRecord::plain(*message),
+                start + Duration::from_millis(10 * n as u64),
+            );
+        }
+        tracker.finish(passed)
+    }
+
+    fn outcomes(tests: &[TestCase]) -> Vec<(&str, TestStatus)> {
+        tests.iter().map(|t| (t.name.as_str(), t.status)).collect()
+    }
+
+    #[test]
+    fn all_tests_pass() {
+        let tests = track(
+            &[
+                "(1/3) running `first`...",
+                "(2/3) ignoring `second`...",
+                "(3/3) running `third`...",
+                "(3/3) `third` passed",
+                "all tests passed!",
+            ],
+            true,
+        );
+        assert_eq!(
+            outcomes(&tests),
+            &[
+                ("first", TestStatus::Passed),
+                ("second", TestStatus::Ignored),
+                ("third", TestStatus::Passed)
+            ]
+        );
+        assert_eq!(tests[0].duration_ms, 10);
+    }
+
+    #[test]
+    fn panic_fails_the_running_test() {
+        let tests = track(
+            &[
+                "(1/2) running `first`...",
+                "(2/2) running `second`...",
+                "panicked at 'assertion failed: false'",
+            ],
+            false,
+        );
+        assert_eq!(
+            outcomes(&tests),
+            &[
+                ("first", TestStatus::Passed),
+                ("second", TestStatus::Failed)
+            ]
+        );
+        assert_eq!(
+            tests[1].message.as_deref(),
+            Some("panicked at 'assertion failed: false'")
+        );
+    }
+}
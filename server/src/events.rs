@@ -0,0 +1,75 @@
+use embedded_ci_common::{job, Uuid};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Number of events buffered per job before slow subscribers start missing some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// What a subscriber gets for a job.
+pub enum Subscription {
+    /// The job is still active, its events will arrive over the receiver.
+    Live(broadcast::Receiver<job::JobEvent>),
+    /// The job has already finished.
+    Finished(job::JobResult),
+}
+
+/// Fan-out of live job events to the streaming clients.
+///
+/// A channel only exists for a job while someone is subscribed to it, events published for a
+/// job nobody listens to are dropped.
+#[derive(Default)]
+pub struct JobEvents {
+    channels: Mutex<HashMap<Uuid, broadcast::Sender<job::JobEvent>>>,
+}
+
+impl JobEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to the events of the job with `id`.
+    ///
+    /// `finished_job_queue` is checked under the same lock [`JobEvents::finish`] takes, so a
+    /// subscriber either sees the result in the queue or receives the final event.
+    pub fn subscribe(
+        &self,
+        id: Uuid,
+        finished_job_queue: &Arc<Mutex<VecDeque<job::JobResult>>>,
+    ) -> Subscription {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(result) = finished_job_queue
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|j| j.id == id)
+        {
+            return Subscription::Finished(result.clone());
+        }
+        Subscription::Live(
+            channels
+                .entry(id)
+                .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+                .subscribe(),
+        )
+    }
+
+    /// Publish an event of the job with `id` to its subscribers, if any.
+    pub fn publish(&self, id: Uuid, event: job::JobEvent) {
+        if let Some(sender) = self.channels.lock().unwrap().get(&id) {
+            // Only fails when all the subscribers are gone
+            let _ = sender.send(event);
+        }
+    }
+
+    /// Publish the result of a finished job and close its channel.
+    ///
+    /// Must be called after the result has been made available in the finished queue.
+    pub fn finish(&self, result: &job::JobResult) {
+        if let Some(sender) = self.channels.lock().unwrap().remove(&result.id) {
+            let _ = sender.send(job::JobEvent::Finished {
+                result: result.clone(),
+            });
+        }
+    }
+}
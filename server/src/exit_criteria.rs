@@ -0,0 +1,125 @@
+//! Exit criteria of a task, ending runs before the core halts by itself.
+
+use crate::backend::Address;
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::image::ImageInfo;
+use embedded_ci_common::job::{
+    ExitCondition, ExitCriterion, ExitOutcome, LogLevel, LogRecord, RunFailure,
+};
+use regex::Regex;
+use std::time::Duration;
+
+enum Check {
+    LogMatches(Regex),
+    LogLevel(LogLevel),
+    After(Duration),
+    Symbol(Address),
+}
+
+struct Criterion {
+    check: Check,
+    condition: ExitCondition,
+    outcome: ExitOutcome,
+}
+
+/// A criterion that was met.
+pub struct Met<'a> {
+    condition: &'a ExitCondition,
+    outcome: ExitOutcome,
+}
+
+impl Met<'_> {
+    /// Outcome of the run, `None` when it passed.
+    pub fn failure(&self) -> Option<RunFailure> {
+        match self.outcome {
+            ExitOutcome::Pass => None,
+            ExitOutcome::Fail => Some(RunFailure::ExitCriterion {
+                condition: self.condition.clone(),
+            }),
+        }
+    }
+
+    pub fn condition(&self) -> &ExitCondition {
+        self.condition
+    }
+}
+
+/// The exit criteria of a task, ready to be checked during a run.
+pub struct ExitCriteria {
+    criteria: Vec<Criterion>,
+}
+
+impl ExitCriteria {
+    /// Compile `criteria`, the symbols they refer to are looked up in `image`.
+    pub fn new(criteria: &[ExitCriterion], image: &ImageInfo) -> Result<Self, RunnerError> {
+        let criteria = criteria
+            .iter()
+            .map(|criterion| {
+                let check = match &criterion.when {
+                    ExitCondition::LogMatches(pattern) => Check::LogMatches(
+                        Regex::new(pattern)
+                            .map_err(|e| anyhow!("Invalid pattern '{}': {}", pattern, e))?,
+                    ),
+                    ExitCondition::LogLevel(level) => Check::LogLevel(*level),
+                    ExitCondition::AfterSecs(secs) => {
+                        Check::After(Duration::from_secs((*secs).into()))
+                    }
+                    ExitCondition::Symbol(name) => Check::Symbol(Address(
+                        image
+                            .function(name)
+                            .map_err(|e| RunnerError::InvalidImage(e.to_string()))?,
+                    )),
+                };
+                Ok(Criterion {
+                    check,
+                    condition: criterion.when.clone(),
+                    outcome: criterion.outcome,
+                })
+            })
+            .collect::<Result<_, RunnerError>>()?;
+        Ok(Self { criteria })
+    }
+
+    /// Addresses of the symbols the core has to be halted at.
+    pub fn breakpoints(&self) -> Vec<Address> {
+        self.criteria
+            .iter()
+            .filter_map(|criterion| match criterion.check {
+                Check::Symbol(address) => Some(address),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The first criterion met by one of the `records`.
+    pub fn check_records(&self, records: &[LogRecord]) -> Option<Met<'_>> {
+        records.iter().find_map(|record| {
+            self.find(|check| match check {
+                Check::LogMatches(pattern) => pattern.is_match(&record.message),
+                Check::LogLevel(level) => record.level.is_some_and(|l| l >= *level),
+                _ => false,
+            })
+        })
+    }
+
+    /// The first criterion met once the firmware has run for `elapsed`.
+    pub fn check_elapsed(&self, elapsed: Duration) -> Option<Met<'_>> {
+        self.find(|check| matches!(check, Check::After(after) if elapsed >= *after))
+    }
+
+    /// The first criterion met by the core halting at `pc`.
+    pub fn check_halt(&self, pc: u32) -> Option<Met<'_>> {
+        self.find(|check| matches!(check, Check::Symbol(address) if address.0 == pc))
+    }
+
+    fn find(&self, met: impl Fn(&Check) -> bool) -> Option<Met<'_>> {
+        self.criteria
+            .iter()
+            .find(|criterion| met(&criterion.check))
+            .map(|criterion| Met {
+                condition: &criterion.condition,
+                outcome: criterion.outcome,
+            })
+    }
+}
@@ -0,0 +1,108 @@
+//! Images last flashed on each target, so that flashing can be skipped when a target already
+//! holds the images of a task.
+
+use crate::backend::Connection;
+use crate::runner::RunnerError;
+use embedded_ci_common::image::ImageInfo;
+use embedded_ci_common::{job::Image, ProbeSerial};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Digests of the images last flashed through each probe.
+///
+/// Only lives as long as the server, the first run after a restart always flashes.
+#[derive(Default)]
+pub struct FlashCache {
+    flashed: Mutex<HashMap<ProbeSerial, u64>>,
+}
+
+impl FlashCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `digest` is the one of the images last flashed through the probe.
+    pub fn holds(&self, probe_serial: &ProbeSerial, digest: u64) -> bool {
+        self.flashed.lock().unwrap().get(probe_serial) == Some(&digest)
+    }
+
+    /// Forget what was flashed through the probe, its contents are unknown once flashing starts.
+    pub fn forget(&self, probe_serial: &ProbeSerial) {
+        self.flashed.lock().unwrap().remove(probe_serial);
+    }
+
+    /// Record that the images with `digest` have been flashed through the probe.
+    pub fn remember(&self, probe_serial: &ProbeSerial, digest: u64) {
+        self.flashed
+            .lock()
+            .unwrap()
+            .insert(probe_serial.clone(), digest);
+    }
+}
+
+/// Digest of the contents of `images`, in order.
+pub fn digest(images: &[Image]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for image in images {
+        image.format.hash(&mut hasher);
+        image.binary.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Number of samples read back from each section.
+const SAMPLES_PER_SECTION: usize = 8;
+/// Size of each sample, in bytes.
+const SAMPLE_SIZE: usize = 64;
+
+/// Read back samples of `images` from the target, returns whether they all match.
+///
+/// Catches the targets flashed by something else than the server. Reading back whole images
+/// over the probe takes about as long as flashing them, so only up to `SAMPLES_PER_SECTION`
+/// samples spread over each section, its start and end included, are compared. A firmware
+/// changing a few bytes of its own flash in between goes unnoticed.
+pub fn samples_match(
+    connection: &mut dyn Connection,
+    images: &[ImageInfo],
+) -> Result<bool, RunnerError> {
+    let mut buffer = Vec::new();
+    let sections = images.iter().flat_map(|image| image.sections.iter());
+    for (load_range, data) in
+        sections.filter_map(|section| Some((section.load_range.as_ref()?, &section.data)))
+    {
+        for sample in samples(data.len()) {
+            let expected = &data[sample.clone()];
+            buffer.resize(expected.len(), 0);
+            connection.read_memory(load_range.start as u32 + sample.start as u32, &mut buffer)?;
+            if buffer != expected {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Ranges of the samples of data `len` bytes long, short data is sampled whole.
+fn samples(len: usize) -> Vec<Range<usize>> {
+    if len <= SAMPLES_PER_SECTION * SAMPLE_SIZE {
+        return (0..len)
+            .step_by(SAMPLE_SIZE)
+            .map(|start| start..len.min(start + SAMPLE_SIZE))
+            .collect();
+    }
+    let step = (len - SAMPLE_SIZE) / (SAMPLES_PER_SECTION - 1);
+    (0..SAMPLES_PER_SECTION)
+        .map(|index| {
+            // The last sample ends with the data, whatever the rounding of `step`
+            let start = match index == SAMPLES_PER_SECTION - 1 {
+                true => len - SAMPLE_SIZE,
+                false => index * step,
+            };
+            start..start + SAMPLE_SIZE
+        })
+        .collect()
+}
@@ -0,0 +1,76 @@
+//! Input scripts sent to the firmware over an RTT down channel while it runs.
+
+use crate::backend::Connection;
+use crate::runner::RunnerError;
+use anyhow::anyhow;
+use embedded_ci_common::job::{InputStep, LogRecord, RttInput};
+use regex::Regex;
+use std::collections::VecDeque;
+
+enum Step {
+    Expect(Regex),
+    /// Bytes still to be sent, writes to a full down channel only send part of them.
+    Send(Vec<u8>),
+}
+
+/// An input script being executed.
+pub struct InputScript {
+    channel: usize,
+    steps: VecDeque<Step>,
+}
+
+impl InputScript {
+    /// Prepare `input` to be sent over the down channel `channel`.
+    pub fn new(input: &RttInput, channel: usize) -> Result<Self, RunnerError> {
+        let steps = input
+            .steps
+            .iter()
+            .map(|step| match step {
+                InputStep::Expect(pattern) => Regex::new(pattern)
+                    .map(Step::Expect)
+                    .map_err(|e| anyhow!("Invalid pattern '{}': {}", pattern, e)),
+                InputStep::Send(text) => Ok(Step::Send(text.as_bytes().to_vec())),
+                InputStep::SendB64(data) => base64::decode(data)
+                    .map(Step::Send)
+                    .map_err(|e| anyhow!("Invalid bytes to send: {}", e)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { channel, steps })
+    }
+
+    /// Advance the script with the records logged since the last call.
+    ///
+    /// Data is sent as soon as the expectations before it are met, so a record logged after a
+    /// matching one is only checked against the next expectation once the data in between has
+    /// been sent.
+    pub fn advance(
+        &mut self,
+        records: &[LogRecord],
+        connection: &mut dyn Connection,
+    ) -> Result<(), RunnerError> {
+        for record in records {
+            if !self.send(connection)? {
+                return Ok(());
+            }
+            if matches!(self.steps.front(), Some(Step::Expect(pattern)) if pattern.is_match(&record.message))
+            {
+                self.steps.pop_front();
+            }
+        }
+        self.send(connection)?;
+        Ok(())
+    }
+
+    /// Send the data that is due, returns whether all of it could be sent.
+    fn send(&mut self, connection: &mut dyn Connection) -> Result<bool, RunnerError> {
+        while let Some(Step::Send(bytes)) = self.steps.front_mut() {
+            let count = connection.write_rtt(self.channel, bytes)?;
+            bytes.drain(..count);
+            if !bytes.is_empty() {
+                return Ok(false);
+            }
+            self.steps.pop_front();
+        }
+        Ok(true)
+    }
+}
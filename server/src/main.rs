@@ -4,7 +4,7 @@
 
 use log::*;
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
 };
 use tokio::signal;
@@ -13,9 +13,19 @@ use embedded_ci_common::ServerStatus;
 
 mod app;
 mod auth;
+mod backend;
+mod backtrace;
 mod cli;
+mod defmt_test;
+mod events;
+mod exit_criteria;
+mod flash_cache;
+mod input;
 mod routes;
 mod runner;
+mod semihosting;
+mod stats;
+mod store;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -44,30 +54,106 @@ async fn main() -> anyhow::Result<()> {
 
     let (register_job_tx, register_job_rx) = tokio::sync::mpsc::channel(max_jobs_in_queue);
 
+    let (cancel_job_tx, cancel_job_rx) = tokio::sync::mpsc::channel(max_jobs_in_queue);
+
     let (finished_job_tx, finished_job_rx) = tokio::sync::mpsc::channel(max_jobs_in_queue);
 
-    let server_status = Arc::new(Mutex::new(ServerStatus::default()));
+    let (mut job_store, restored_jobs) =
+        match store::JobStore::open(&cli.server_configs.job_store.0) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error in startup: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+    let target_stats = match stats::TargetStatsStore::open(
+        &cli.server_configs.target_stats.0,
+        cli.server_configs.flash_operations_warning,
+    ) {
+        Ok(v) => Arc::new(Mutex::new(v)),
+        Err(e) => {
+            println!("Error in startup: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let finished_job_queue = Arc::new(Mutex::new(VecDeque::with_capacity(max_jobs_in_queue)));
+    let mut server_status = ServerStatus::default();
+
+    let mut finished_job_queue = VecDeque::with_capacity(max_jobs_in_queue);
+    let restored_finished_to_skip = restored_jobs
+        .finished
+        .len()
+        .saturating_sub(max_jobs_in_queue);
+    for (index, job_result) in restored_jobs.finished.into_iter().enumerate() {
+        if index < restored_finished_to_skip {
+            job_store.job_cleared(job_result.id);
+            continue;
+        }
+        server_status.job_restored(job_result.id);
+        finished_job_queue.push_back(job_result);
+    }
+    for job in restored_jobs.queued.iter() {
+        server_status.job_enqueued(job.id);
+    }
+    info!(
+        "Restored {} finished and {} queued job(s)",
+        finished_job_queue.len(),
+        restored_jobs.queued.len()
+    );
+
+    let server_status = Arc::new(Mutex::new(server_status));
+
+    let finished_job_queue = Arc::new(Mutex::new(finished_job_queue));
+
+    let job_store = Arc::new(Mutex::new(job_store));
+
+    let job_events = Arc::new(events::JobEvents::new());
 
     let _rocket_handle = tokio::spawn(routes::serve(
         finished_job_queue.clone(),
         register_job_tx,
+        cancel_job_tx,
         targets,
         server_status.clone(),
+        job_store.clone(),
+        job_events.clone(),
+        target_stats.clone(),
     ));
 
     let _finished_job_collector = tokio::spawn(app::finished_job_collector(
         finished_job_queue.clone(),
         finished_job_rx,
         server_status.clone(),
+        job_store.clone(),
+        job_events.clone(),
         max_jobs_in_queue,
     ));
 
+    let virtual_probes: HashMap<_, _> = cli
+        .probe_configs
+        .iter()
+        .filter_map(|(serial, info)| Some((serial.clone(), info.qemu.clone()?)))
+        .collect();
+    let qemu_backend = Arc::new(backend::QemuBackend::new(virtual_probes.clone()));
+    let backend = virtual_probes.into_keys().fold(
+        backend::Router::new(Arc::new(backend::ProbeRsBackend::new())),
+        |router, probe_serial| router.route(probe_serial, qemu_backend.clone()),
+    );
+
     let _backend_handle = tokio::spawn(app::run(
+        restored_jobs.queued,
         register_job_rx,
+        cancel_job_rx,
         finished_job_tx,
-        server_status.clone(),
+        app::Context {
+            server_status: server_status.clone(),
+            job_store,
+            job_events,
+            target_stats,
+            backend: Arc::new(backend),
+            flash_cache: Arc::new(flash_cache::FlashCache::new()),
+        },
         cli.probe_configs,
         cli.server_configs,
     ));
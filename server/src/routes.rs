@@ -1,10 +1,19 @@
-use embedded_ci_common::{job, JobStatus, ServerStatus, Targets, Uuid};
+use crate::events::{JobEvents, Subscription};
+use crate::stats::TargetStatsStore;
+use crate::store::JobStore;
+use embedded_ci_common::{
+    job, report, JobStatus, ProbeSerial, ServerStatus, TargetStats, Targets, Uuid,
+};
 use rocket::{
+    delete,
     fairing::{Fairing, Info, Kind},
     get,
-    http::{Header, Status},
+    http::{ContentType, Header, Status},
     post,
-    response::status::{Accepted, Custom},
+    response::{
+        status::{Accepted, Custom},
+        stream::{Event, EventStream},
+    },
     routes,
     serde::json::Json,
     Ignite, Request, Response, Rocket, State,
@@ -13,7 +22,7 @@ use std::{
     collections::VecDeque,
     sync::{Arc, Mutex},
 };
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
 
 #[derive(rocket::Responder)]
 pub enum PostJobError {
@@ -32,12 +41,18 @@ fn post_job(
     register_job_tx: &State<mpsc::Sender<job::Job>>,
     server_status: &State<Arc<Mutex<ServerStatus>>>,
     targets: &State<Targets>,
+    job_store: &State<Arc<Mutex<JobStore>>>,
 ) -> Result<Accepted<Json<job::Job>>, PostJobError> {
     let job =
         job::Job::from_desc(job_desc.0, &targets).map_err(|e| PostJobError::InvalidJob(Json(e)))?;
+    // Held across sending so the job is recorded as enqueued before the scheduler can start it,
+    // which it does concurrently
+    let mut job_store = job_store.lock().unwrap();
+    let mut server_status = server_status.lock().unwrap();
     match register_job_tx.try_send(job.clone()) {
         Ok(_) => {
-            server_status.lock().unwrap().job_enqueued(job.id);
+            job_store.job_enqueued(&job);
+            server_status.job_enqueued(job.id);
             Ok(Accepted(Json(job)))
         }
         Err(mpsc::error::TrySendError::Full(_)) => Err(PostJobError::TooManyJobs(())),
@@ -45,13 +60,12 @@ fn post_job(
     }
 }
 
-#[get("/job/by-id/<id>")]
-fn get_job_by_id(
-    _token: crate::auth::Token,
+/// Result of the finished job with `id`, or the status of a job that has not finished yet.
+fn finished_job_result(
     id: Uuid,
-    server_status: &State<Arc<Mutex<ServerStatus>>>,
-    finished_job_queue: &State<Arc<Mutex<VecDeque<job::JobResult>>>>,
-) -> Result<Custom<Json<job::JobResult>>, Custom<Json<JobStatus>>> {
+    server_status: &Mutex<ServerStatus>,
+    finished_job_queue: &Mutex<VecDeque<job::JobResult>>,
+) -> Result<job::JobResult, Custom<Json<JobStatus>>> {
     let server_status = server_status.lock().unwrap();
     match server_status.job_status(id) {
         v @ JobStatus::NotFound => Err(Custom(Status::NotFound, Json(v))),
@@ -64,7 +78,7 @@ fn get_job_by_id(
             .find(|&j| j.id == id)
             .cloned()
         {
-            Some(job_result) => return Ok(Custom(Status::Found, Json(job_result.clone()))),
+            Some(job_result) => Ok(job_result),
             None => unreachable!(
                 "Job finished in ServerStatus but not found in the finished queue - bug?"
             ),
@@ -72,6 +86,114 @@ fn get_job_by_id(
     }
 }
 
+#[get("/job/by-id/<id>")]
+fn get_job_by_id(
+    _token: crate::auth::Token,
+    id: Uuid,
+    server_status: &State<Arc<Mutex<ServerStatus>>>,
+    finished_job_queue: &State<Arc<Mutex<VecDeque<job::JobResult>>>>,
+) -> Result<Custom<Json<job::JobResult>>, Custom<Json<JobStatus>>> {
+    let job_result = finished_job_result(id, server_status, finished_job_queue)?;
+    Ok(Custom(Status::Found, Json(job_result)))
+}
+
+/// The result of a finished job as JUnit XML.
+#[get("/job/by-id/<id>/junit.xml")]
+fn get_job_junit_by_id(
+    _token: crate::auth::Token,
+    id: Uuid,
+    server_status: &State<Arc<Mutex<ServerStatus>>>,
+    finished_job_queue: &State<Arc<Mutex<VecDeque<job::JobResult>>>>,
+) -> Result<(ContentType, String), Custom<Json<JobStatus>>> {
+    let job_result = finished_job_result(id, server_status, finished_job_queue)?;
+    Ok((ContentType::XML, report::junit_xml(&job_result)))
+}
+
+/// The result of a finished job in the Test Anything Protocol.
+#[get("/job/by-id/<id>/tap")]
+fn get_job_tap_by_id(
+    _token: crate::auth::Token,
+    id: Uuid,
+    server_status: &State<Arc<Mutex<ServerStatus>>>,
+    finished_job_queue: &State<Arc<Mutex<VecDeque<job::JobResult>>>>,
+) -> Result<(ContentType, String), Custom<Json<JobStatus>>> {
+    let job_result = finished_job_result(id, server_status, finished_job_queue)?;
+    Ok((ContentType::Plain, report::tap(&job_result)))
+}
+
+/// Stream the events of a job as they happen, ending once the job has finished.
+///
+/// The first event is the current status of the job, a job that has already finished only
+/// gets its result.
+#[get("/job/by-id/<id>/stream")]
+fn stream_job_by_id(
+    _token: crate::auth::Token,
+    id: Uuid,
+    server_status: &State<Arc<Mutex<ServerStatus>>>,
+    finished_job_queue: &State<Arc<Mutex<VecDeque<job::JobResult>>>>,
+    job_events: &State<Arc<JobEvents>>,
+) -> Result<EventStream![], Custom<Json<JobStatus>>> {
+    if let v @ JobStatus::NotFound = server_status.lock().unwrap().job_status(id) {
+        return Err(Custom(Status::NotFound, Json(v)));
+    }
+    let subscription = job_events.subscribe(id, finished_job_queue);
+    // Read after subscribing, so a transition in between is not missed
+    let status = server_status.lock().unwrap().job_status(id);
+    Ok(EventStream! {
+        match subscription {
+            Subscription::Finished(result) => {
+                yield Event::json(&job::JobEvent::Finished { result });
+            }
+            Subscription::Live(mut events) => {
+                yield Event::json(&job::JobEvent::Status { status });
+                loop {
+                    match events.recv().await {
+                        Ok(event) => yield Event::json(&event),
+                        // Lines were dropped for this slow client, the others are unaffected
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[derive(rocket::Responder)]
+pub enum CancelJobError {
+    #[response(status = 404)]
+    NotFound(Json<JobStatus>),
+    #[response(status = 409)]
+    AlreadyFinished(Json<JobStatus>),
+    #[response(status = 503)]
+    TooManyCancellations(()),
+    #[response(status = 500)]
+    InternalQueueClosed(()),
+}
+
+/// Cancel the job with `id`, dropping it if it is still queued.
+///
+/// Cancellation is best-effort while its targets are being flashed: the image being written is
+/// completed before the runs stop.
+#[delete("/job/by-id/<id>")]
+fn cancel_job_by_id(
+    _token: crate::auth::Token,
+    id: Uuid,
+    cancel_job_tx: &State<mpsc::Sender<Uuid>>,
+    server_status: &State<Arc<Mutex<ServerStatus>>>,
+) -> Result<Accepted<Json<JobStatus>>, CancelJobError> {
+    match server_status.lock().unwrap().job_status(id) {
+        v @ JobStatus::NotFound => return Err(CancelJobError::NotFound(Json(v))),
+        v @ JobStatus::Finished => return Err(CancelJobError::AlreadyFinished(Json(v))),
+        JobStatus::InQueue | JobStatus::Running => {}
+    }
+    match cancel_job_tx.try_send(id) {
+        Ok(_) => Ok(Accepted(Json(server_status.lock().unwrap().job_status(id)))),
+        Err(mpsc::error::TrySendError::Full(_)) => Err(CancelJobError::TooManyCancellations(())),
+        Err(mpsc::error::TrySendError::Closed(_)) => Err(CancelJobError::InternalQueueClosed(())),
+    }
+}
+
 #[get("/job/last")]
 fn last_job(
     _token: crate::auth::Token,
@@ -99,6 +221,20 @@ fn targets(_token: crate::auth::Token, targets: &State<Targets>) -> Json<Targets
     Json(Clone::clone(&targets))
 }
 
+#[get("/targets/<serial>/stats")]
+fn target_stats(
+    _token: crate::auth::Token,
+    serial: &str,
+    targets: &State<Targets>,
+    target_stats: &State<Arc<Mutex<TargetStatsStore>>>,
+) -> Result<Json<TargetStats>, Status> {
+    let probe_serial = ProbeSerial(serial.to_owned());
+    if targets.find_by_probe_serial(&probe_serial).is_none() {
+        return Err(Status::NotFound);
+    }
+    Ok(Json(target_stats.lock().unwrap().get(&probe_serial)))
+}
+
 pub struct CORS;
 
 #[rocket::async_trait]
@@ -114,29 +250,49 @@ impl Fairing for CORS {
         response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
-            "POST, GET, PATCH, OPTIONS",
+            "POST, GET, PATCH, DELETE, OPTIONS",
         ));
         response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn serve(
     finished_job_queue: Arc<Mutex<VecDeque<job::JobResult>>>,
     register_job_tx: mpsc::Sender<job::Job>,
+    cancel_job_tx: mpsc::Sender<Uuid>,
     targets: Targets,
     server_status: Arc<Mutex<ServerStatus>>,
+    job_store: Arc<Mutex<JobStore>>,
+    job_events: Arc<JobEvents>,
+    target_stats: Arc<Mutex<TargetStatsStore>>,
 ) -> Result<Rocket<Ignite>, rocket::Error> {
     rocket::build()
         .attach(CORS)
         .mount(
             "/",
-            routes![targets, post_job, get_job_by_id, status, last_job],
+            routes![
+                targets,
+                target_stats,
+                post_job,
+                get_job_by_id,
+                get_job_junit_by_id,
+                get_job_tap_by_id,
+                stream_job_by_id,
+                cancel_job_by_id,
+                status,
+                last_job
+            ],
         )
         .manage(finished_job_queue)
         .manage(register_job_tx)
+        .manage(cancel_job_tx)
         .manage(targets)
         .manage(server_status)
+        .manage(job_store)
+        .manage(job_events)
+        .manage(target_stats)
         .launch()
         .await
 }
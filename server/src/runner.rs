@@ -1,33 +1,36 @@
+use crate::backend::{
+    Address, Backend, Connection, CoreRegister, Firmware, FlashReport, Halt, RttChannelInfo,
+    RttChannels, Symbols, VectorTable,
+};
+use crate::backtrace;
+use crate::exit_criteria::{ExitCriteria, Met};
+use crate::flash_cache::{self, FlashCache};
+use crate::input::InputScript;
+use crate::semihosting;
 use anyhow::anyhow;
 use defmt_decoder::{DecodeError, Locations as DefmtLocations, Table as DefmtTable};
-use embedded_ci_common::{ProbeSerial, TargetName};
-use log::*;
-use object::{File, Object, ObjectSection, ObjectSymbol};
-use probe_rs::rtt::{Error as RttError, Rtt, ScanRegion, UpChannel};
-use probe_rs::{
-    flashing::{DownloadOptions, FileDownloadError, FlashError},
-    MemoryInterface, RegisterId, Session,
+use embedded_ci_common::image::{ImageError, ImageInfo};
+use embedded_ci_common::{
+    job::{
+        ExecutionMode, ImageFormat, LogLevel, LogRecord, RttChannel, RttInput, RunFailure, Task,
+    },
+    ProbeSerial, Target, TargetName,
 };
-use probe_rs::{CoreStatus, DebugProbeError, HaltReason, Probe, ProbeCreationError};
-use std::sync::Mutex;
-use std::thread;
+use log::*;
+use probe_rs::flashing::{FileDownloadError, FlashError};
+use probe_rs::rtt::Error as RttError;
+use probe_rs::{DebugProbeError, ProbeCreationError};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
-use std::{io::Cursor, sync::Arc};
 
-use crate::app::unroll_error;
-
-const THUMB_BIT: u32 = 1;
-const SP: RegisterId = RegisterId(13);
-const LR: RegisterId = RegisterId(14);
-const PC: RegisterId = RegisterId(15);
-const PSR: RegisterId = RegisterId(16);
-const VTOR: Address = Address(0xE000ED08);
+/// Number of log lines reported along with a stalled run.
+const STALLED_CONTEXT_LINES: usize = 5;
 
 /// Error definitions for runner.
 #[derive(thiserror::Error, Debug)]
 pub enum RunnerError {
-    #[error("ELF file error: {0}")]
-    ElfError(String),
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
     #[error("A flashing error occurred")]
     FlashError(#[from] FlashError),
     #[error("A file download error occurred")]
@@ -40,23 +43,39 @@ pub enum RunnerError {
     ProbeRs(#[from] probe_rs::Error),
     #[error("An RTT error occurred")]
     ProbeRsRtt(#[from] RttError),
+    #[error("The run was cancelled")]
+    Cancelled(Vec<LogRecord>),
+    #[error("{0}")]
+    Failed(RunFailure, Vec<LogRecord>),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
-// Internal helper to keep addresses and raw `u32`s apart.
-struct Address(pub u32);
+/// Outcome of a run that completed without failing.
+#[derive(Debug)]
+pub struct RunOutput {
+    /// Logs captured during the run.
+    pub logs: Vec<LogRecord>,
+    /// Exit code signalled by the firmware, if it could be read.
+    pub exit_code: Option<i32>,
+}
 
 /// After the can of the binary is complete this enum holds the best guess of the kind of RTT
 /// that is used by the binary.
 enum RttType {
     Defmt {
         table: DefmtTable,
-        _locations: DefmtLocations,
+        locations: DefmtLocations,
     },
     PlainText,
 }
 
+/// Why the main loop of a run ended.
+enum Stop<'c> {
+    Halt(Halt),
+    Criterion(Met<'c>),
+}
+
 /// The main runner for embedded targets.
 ///
 /// From here all access and handling of the embedded target happens as it's run by the service.
@@ -64,429 +83,672 @@ pub struct Runner<'a> {
     target_name: &'a TargetName,
     probe_serial: &'a ProbeSerial,
     probe_speed_khz: Option<u32>,
-    from_ram: bool,
-    symbols: Symbols,
-    vector_table: VectorTable,
+    firmware: Firmware<'a>,
+    flash_cache: &'a FlashCache,
+    attached: bool,
+    flash_skipped: bool,
+    flash_report: Option<FlashReport>,
     rtt_type: RttType,
-    elf_bytes: &'a [u8],
-}
-
-/// Holds important symbol addresses.
-struct Symbols {
-    main: Address,
-    rtt: Address,
-}
-
-/// Holds important vector table addresses.
-struct VectorTable {
-    start: Address,
-    stack_pointer: Address,
-    reset: Address,
-    hardfault: Address,
+    rtt_channels: Option<&'a [RttChannel]>,
+    rtt_input: Option<&'a RttInput>,
+    no_rtt: bool,
+    exit_criteria: ExitCriteria,
+    inactivity_timeout_secs: Option<u32>,
 }
 
 impl<'a> Runner<'a> {
-    /// Create a new runner, for running a binary on a target, based on the ELF files and settings.
+    /// Create a new runner, for running the binary of `task` on a target, based on its image
+    /// and settings.
     pub fn new(
-        elf_bytes: &'a [u8],
-        target_name: &'a TargetName,
-        probe_serial: &'a ProbeSerial,
+        task: &'a Task,
+        target: &'a Target,
         probe_speed_khz: Option<u32>,
+        flash_cache: &'a FlashCache,
     ) -> Result<Runner<'a>, RunnerError> {
-        let elf = File::parse(elf_bytes)
-            .map_err(|e| anyhow!("ELF parsing error, file is not an ELF file: '{}'", e))?;
-
-        let mut rtt = None;
-        let mut main = None;
-
-        for symbol in elf.symbols() {
-            let name = match symbol.name() {
-                Ok(name) => name,
-                Err(_) => continue,
-            };
-
-            if name == "main" {
-                main = Some(symbol.address() as u32 & !THUMB_BIT);
-            }
-
-            if name == "_SEGGER_RTT" {
-                rtt = Some(symbol.address() as u32);
-            }
-
-            if main.is_some() && rtt.is_some() {
-                break;
-            }
-        }
+        let images = task
+            .images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| match index == task.primary {
+                true => ImageInfo::parse(&image.binary, &image.format),
+                false => ImageInfo::parse_secondary(&image.binary, &image.format),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| RunnerError::InvalidImage(e.to_string()))?;
+        let primary = task.primary_image();
+        let image = &images[task.primary];
 
         let symbols = Symbols {
-            main: Address(main.ok_or(anyhow!("'main' symbol not found"))?),
-            rtt: Address(rtt.ok_or(anyhow!(
-                "'_SEGGER_RTT' symbol not found, without RTT this CI tool will not work"
-            ))?),
+            // Images without symbols are started right from reset
+            entry: match task.skip_run_to_entry || !primary.format.is_elf() {
+                true => None,
+                false => Some(Address(
+                    image
+                        .function(task.entry_symbol.as_deref().unwrap_or("main"))
+                        .map_err(|e| RunnerError::InvalidImage(e.to_string()))?,
+                )),
+            },
+            // Without the symbol the control block is searched for in RAM
+            rtt: image.symbol("_SEGGER_RTT").map(Address),
         };
 
-        let important_sections = [".vector_table", ".text", ".rodata", ".data"];
-        let mut vector_table = None;
-        let mut from_ram = false;
-
-        for section in elf.sections() {
-            let name = match section.name() {
-                Ok(name) => name,
-                Err(_) => continue,
-            };
-
-            let addr = section.address();
-
-            if important_sections.contains(&name) {
-                if addr % 4 != 0 {
-                    // Can sections be unaligned?
-                    return Err(RunnerError::ElfError(format!(
-                        "Section '{}' is not 4 byte aligned",
-                        name
-                    )));
-                }
-
-                // If it is the vector table, get important addresses from it
-                if name == ".vector_table" {
-                    let data = section.data().map_err(|_| {
-                        RunnerError::ElfError(format!("There is no data in section '{}'", name))
-                    })?;
-                    if data.len() < 16 {
-                        return Err(RunnerError::ElfError(format!(
-                            "Section '{}' is too small, size = {} bytes",
-                            name,
-                            data.len()
-                        )));
-                    }
-
-                    let vt: Vec<_> = data
-                        .chunks_exact(4)
-                        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
-                        .collect();
-
-                    vector_table = Some(VectorTable {
-                        start: Address(addr.try_into().map_err(|_| {
-                            RunnerError::ElfError(format!(
-                                "The address of section '{}' is not 32-bit",
-                                name
-                            ))
-                        })?),
-                        stack_pointer: Address(vt[0]),
-                        reset: Address(vt[1]),
-                        hardfault: Address(vt[3]),
-                    });
-
-                    from_ram = addr >= 0x2000_0000;
-                }
-            }
-        }
-
-        let rtt_type = if let Some(table) = defmt_decoder::Table::parse(&elf_bytes)? {
-            let locations = table.get_locations(&elf_bytes)?;
+        let defmt_table = match primary.format {
+            ImageFormat::Elf => defmt_decoder::Table::parse(&primary.binary)?,
+            _ => None,
+        };
+        let rtt_type = if let Some(table) = defmt_table {
+            let locations = table.get_locations(&primary.binary)?;
 
             // TODO: This does not seem like it should be a hard error?
             // if !table.is_empty() && locations.is_empty() {
-            //     return Err(RunnerError::ElfError(
+            //     return Err(RunnerError::InvalidImage(
             //         "'.defmt' symbol found but not enough debug information for defmt, enable debug symbols (debug = 2)".into()
             //     ));
             // } else {
-            RttType::Defmt {
-                table,
-                _locations: locations,
-            }
+            RttType::Defmt { table, locations }
             //}
         } else {
             // The defmt table parsing returned none, so there is no `.defmt` section
             RttType::PlainText
         };
 
+        let exit_criteria = ExitCriteria::new(&task.exit_criteria, image)?;
+
+        // Firmwares linked to RAM are run from RAM even when they are flashed
+        let from_ram =
+            task.execution_mode == ExecutionMode::Ram || image.runs_from_ram(&target.memory_map);
+        // Always there, the primary image is parsed for its vector table
+        let vector_table = image
+            .vector_table
+            .map(|vector_table| VectorTable {
+                start: Address(vector_table.start),
+                stack_pointer: Address(vector_table.stack_pointer),
+                reset: Address(vector_table.reset),
+                hardfault: Address(vector_table.hardfault),
+            })
+            .ok_or_else(|| RunnerError::InvalidImage(ImageError::NoVectorTable.to_string()))?;
+
         Ok(Runner {
-            target_name,
-            probe_serial,
+            target_name: &target.target_name,
+            probe_serial: &target.probe_serial,
             probe_speed_khz,
-            from_ram,
-            symbols,
-            vector_table: vector_table.ok_or(anyhow!("'.vector_table' section not found"))?,
+            firmware: Firmware {
+                images: &task.images,
+                primary,
+                info: images,
+                execution_mode: task.execution_mode,
+                from_ram,
+                symbols,
+                vector_table,
+                breakpoints: exit_criteria.breakpoints(),
+            },
+            flash_cache,
+            attached: false,
+            flash_skipped: false,
+            flash_report: None,
             rtt_type,
-            elf_bytes,
+            rtt_channels: task.rtt_channels.as_deref(),
+            rtt_input: task.rtt_input.as_ref(),
+            no_rtt: task.no_rtt,
+            exit_criteria,
+            inactivity_timeout_secs: task.inactivity_timeout_secs,
         })
     }
 
+    /// Whether the last run reached the target, it did not when attaching to it failed.
+    pub fn attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Whether flashing was skipped during the last run, as the target already held the images.
+    pub fn flash_skipped(&self) -> bool {
+        self.flash_skipped
+    }
+
+    /// What flashing did to the flash memory during the last run, `None` when it was left alone.
+    pub fn flash_report(&self) -> Option<FlashReport> {
+        self.flash_report
+    }
+
     /// Run the `Runner` to completion with a timeout.
+    ///
+    /// Each log record is handed to `log_sink` as soon as it is decoded.
+    ///
+    /// Setting `cancel_flag` stops the run early, the target is halted and the logs captured so
+    /// far are returned in [`RunnerError::Cancelled`]. While flashing it only takes effect
+    /// between images.
     pub fn run(
         &mut self,
-        probe_mutex: &Arc<Mutex<()>>,
+        backend: &dyn Backend,
         barrier: crossbeam::sync::WaitGroup,
+        cancel_flag: &AtomicBool,
         timeout: Duration,
-    ) -> Result<Vec<String>, RunnerError> {
-        let probe = self.get_probe(probe_mutex, self.probe_speed_khz)?;
-
-        debug!("{}: Attaching to target", self.probe_serial);
-        // First we try to connect normally
-        let mut session = match probe.attach(&self.target_name.0, Default::default()) {
-            Ok(v) => v,
-            Err(e) => {
-                // If that fails we fall back to a connect under reset attach
-                warn!(
-                    "{}: Attach failed ({}), trying with attach under reset...",
-                    self.probe_serial, e
-                );
-
-                let probe = self.get_probe(probe_mutex, self.probe_speed_khz)?;
-                probe
-                    .attach_under_reset(&self.target_name.0, Default::default())
-                    .map_err(|_| {
-                        anyhow!(
-                        "Unable to attach to the target, both normal and attach under reset failed"
-                    )
-                    })?
-            }
-        };
-
-        debug!("{}: Starting download of ELF", self.probe_serial);
-        {
-            session.core(0)?.reset_and_halt(Duration::from_secs(3))?;
-
-            let mut opt = DownloadOptions::default();
-            opt.verify = true;
-            opt.keep_unwritten_bytes = true;
-
-            let mut loader = session.target().flash_loader();
-            loader.load_elf_data(&mut Cursor::new(&self.elf_bytes))?;
-
-            loader.commit(&mut session, opt)?;
-        }
-        debug!("{}: Done!", self.probe_serial);
-
-        let mut core = session.core(0)?;
-
-        if self.from_ram {
-            // Fix for ECC RAM, do a dummy write. Thanks to @dirbaio for finding
-            let data = core.read_word_32(self.vector_table.start.0 as _)?;
-            core.write_word_32(self.vector_table.start.0 as _, data)?;
-        }
-
-        core.reset_and_halt(Duration::from_secs(3))?;
-
-        // Check so we have some breakpoint units
-        if core.available_breakpoint_units()? == 0 {
-            error!(
-                "{}: The target does not have any HW breakpoint units?!?! Aborting.",
-                self.probe_serial
-            );
-            return Err(anyhow!(
-                "The target does not have any HW breakpoint units?! Aborting."
-            ))?;
-        }
-
-        debug!("{}: Starting target", self.probe_serial);
-        if self.from_ram {
-            debug!(
-                "{}: Running from RAM (will not halt at main)",
-                self.probe_serial
-            );
-            core.write_core_reg(PC, self.vector_table.reset.0)
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-            core.write_core_reg(SP, self.vector_table.stack_pointer.0)
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-            core.write_word_32(VTOR.0 as _, self.vector_table.start.0)
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-        } else {
-            // Reset the RTT control block
-            core.write_word_32(self.symbols.rtt.0 as _, 0x12341234)
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-
-            // Go to main
-            core.set_hw_breakpoint(self.symbols.main.0 as _)
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-
-            core.run().map_err(|e| RunnerError::UnableToReachMain(e))?;
-            core.wait_for_core_halted(Duration::from_secs(5))
-                .map_err(|e| RunnerError::UnableToReachMain(e))?;
-            const OFFSET: u32 = 44;
-            const FLAG: u32 = 2; // BLOCK_IF_FULL
-            core.write_word_32((self.symbols.rtt.0 + OFFSET) as u64, FLAG)?;
-            debug!("{}: Arrived at 'main'", self.probe_serial);
-            core.clear_hw_breakpoint(self.symbols.main.0 as _)?;
-        }
-
-        if self.from_ram {
-            // We can set breakpoints in RAM so we replace the instruction at the breakpoint
-            // location with the breakpoint instruction instead.
-            core.write_8(
-                (self.vector_table.hardfault.0 as u32 & !THUMB_BIT) as u64,
-                &[0x00, 0xbe],
-            )?;
+        log_sink: &dyn Fn(&LogRecord),
+    ) -> Result<RunOutput, RunnerError> {
+        self.attached = false;
+        let mut connection = backend
+            .attach(self.target_name, self.probe_serial, self.probe_speed_khz)
+            .map_err(|e| match e {
+                RunnerError::DebugProbeError(DebugProbeError::ProbeCouldNotBeCreated(
+                    ProbeCreationError::NotFound,
+                )) => RunnerError::Failed(RunFailure::ProbeNotFound, Vec::new()),
+                e => RunnerError::Failed(
+                    RunFailure::AttachFailed {
+                        error: error_chain(&e),
+                    },
+                    Vec::new(),
+                ),
+            })?;
+        self.attached = true;
+
+        self.check_cancelled(cancel_flag, "before flashing")?;
+        let digest = flash_cache::digest(self.firmware.images);
+        self.flash_report = None;
+        self.flash_skipped = self.holds_images(connection.as_mut(), digest);
+        if self.flash_skipped {
+            info!("{}: Target already holds the images", self.probe_serial);
         } else {
-            core.set_hw_breakpoint((self.vector_table.hardfault.0 as u32 & !THUMB_BIT) as u64)?;
+            self.flash_cache.forget(self.probe_serial);
+            self.flash_report =
+                connection
+                    .flash(&self.firmware, cancel_flag)
+                    .map_err(|e| match e {
+                        RunnerError::Cancelled(logs) => {
+                            debug!("{}: Cancelled while flashing", self.probe_serial);
+                            RunnerError::Cancelled(logs)
+                        }
+                        e => RunnerError::Failed(
+                            RunFailure::FlashFailed {
+                                error: error_chain(&e),
+                            },
+                            Vec::new(),
+                        ),
+                    })?;
+            self.flash_cache.remember(self.probe_serial, digest);
         }
+        self.check_cancelled(cancel_flag, "before preparing the target")?;
+        connection.prepare(&self.firmware).map_err(|e| match e {
+            RunnerError::UnableToReachMain(e) => RunnerError::Failed(
+                RunFailure::UnableToReachMain {
+                    error: error_chain(&e),
+                },
+                Vec::new(),
+            ),
+            e => e,
+        })?;
 
         info!("{}: Barrier reached!", self.probe_serial);
         barrier.wait();
         info!("{}: Barrier passed!", self.probe_serial);
 
-        core.run()?;
+        self.check_cancelled(cancel_flag, "before start")?;
+        connection.run()?;
 
         // Attach to RTT.
-        drop(core);
-        let channel = self.setup_rtt_channel(&mut session)?;
-        let mut core = session.core(0)?;
+        let rtt_channels = match self.no_rtt {
+            true => RttChannels::default(),
+            false => connection.attach_rtt(&self.firmware).map_err(|e| match e {
+                RunnerError::ProbeRsRtt(RttError::ControlBlockNotFound) => {
+                    RunnerError::Failed(RunFailure::RttNotFound, Vec::new())
+                }
+                e => e,
+            })?,
+        };
 
-        let mut buffer = Vec::new();
+        let mut input = match self.rtt_input {
+            Some(input) => {
+                let channel = find_channel(&rtt_channels.down, &input.channel)?;
+                Some(InputScript::new(input, channel.number)?)
+            }
+            None => None,
+        };
+        let mut channels = self.capture(rtt_channels.up)?;
+        let mut logs = Vec::new();
+        let mut collect = |records: Vec<LogRecord>| {
+            for record in records {
+                log_sink(&record);
+                logs.push(record);
+            }
+        };
         let mut read_buf = [0u8; 16 * 1024];
         let start = Instant::now();
+        let mut last_output = start;
+        // Criterion only met by the output read after the core halted
+        let mut met_after_halt = None;
 
-        loop {
+        let stop = loop {
             // thread::sleep(Duration::from_millis(1));
 
             // Read from an RTT channel.
-            let count = channel.read(&mut core, &mut read_buf[..])?;
-            buffer.extend_from_slice(&read_buf[..count]);
+            let (records, received) =
+                read_channels(&mut channels, connection.as_mut(), &mut read_buf)?;
+            if received > 0 {
+                last_output = Instant::now();
+            }
+            if let Some(input) = input.as_mut() {
+                input.advance(&records, connection.as_mut())?;
+            }
+            let met = self.exit_criteria.check_records(&records);
+            collect(records);
 
-            if core.core_halted()? {
+            if let Some(met) = met.or_else(|| self.exit_criteria.check_elapsed(start.elapsed())) {
+                if let Err(e) = connection.halt() {
+                    error!(
+                        "Attempt to halt the core timed out when an exit criterion was met: {e}"
+                    );
+                }
+                break Stop::Criterion(met);
+            }
+
+            if let Some(halt) = connection.halt_status()? {
                 // Read from an RTT channel an extra time.
-                let count = channel
-                    .read(&mut core, &mut read_buf[..])
+                let (records, _) = read_channels(&mut channels, connection.as_mut(), &mut read_buf)
                     .map_err(|e| anyhow!(e))?;
-                buffer.extend_from_slice(&read_buf[..count]);
+                met_after_halt = self.exit_criteria.check_records(&records);
+                collect(records);
+                break Stop::Halt(halt);
+            }
 
-                break;
+            if cancel_flag.load(Ordering::Relaxed) {
+                if let Err(e) = connection.halt() {
+                    error!("Attempt to halt the core timed out when run was cancelled: {e}");
+                }
+                collect(finish_channels(&mut channels));
+                debug!(
+                    "{}: Cancelled, partial log:\n{}",
+                    self.probe_serial,
+                    join_records(&logs)
+                );
+                return Err(RunnerError::Cancelled(logs));
             }
 
             if Instant::now() - start > timeout {
-                if let Err(e) = core.halt(Duration::from_secs(1)) {
+                if let Err(e) = connection.halt() {
                     error!("Attempt to halt the core timed out when run firmware timed out: {e}");
                 }
-                let logs = self.log_to_strings(buffer).unwrap_or_default();
-                let log = logs.join("\n");
+                collect(finish_channels(&mut channels));
                 debug!(
                     "{}: Firmware timeout, partial log:\n{}",
-                    self.probe_serial, log
+                    self.probe_serial,
+                    join_records(&logs)
                 );
-                return Err(anyhow!(
-                    "The firmware reached timeout, partial log:\n{}",
-                    log
-                ))?;
+                return Err(RunnerError::Failed(RunFailure::Timeout, logs));
             }
-        }
 
-        let logs = self.log_to_strings(buffer)?;
-        let log = logs.join("\n");
+            if let Some(silent_secs) = self.inactivity_timeout_secs {
+                if last_output.elapsed() > Duration::from_secs(silent_secs.into()) {
+                    if let Err(e) = connection.halt() {
+                        error!("Attempt to halt the core timed out when run firmware stalled: {e}");
+                    }
+                    collect(finish_channels(&mut channels));
+                    debug!(
+                        "{}: Firmware stalled, partial log:\n{}",
+                        self.probe_serial,
+                        join_records(&logs)
+                    );
+                    let last_lines = logs[logs.len().saturating_sub(STALLED_CONTEXT_LINES)..]
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect();
+                    let reason = RunFailure::Stalled {
+                        silent_secs,
+                        last_lines,
+                    };
+                    return Err(RunnerError::Failed(reason, logs));
+                }
+            }
+        };
 
-        match core.status()? {
-            CoreStatus::Halted(HaltReason::Breakpoint(_)) => {
-                let isr_no = core.read_core_reg::<u32>(PSR)? & 0xff;
+        let records = finish_channels(&mut channels);
+        if let Stop::Halt(_) = stop {
+            met_after_halt = met_after_halt.or_else(|| self.exit_criteria.check_records(&records));
+        }
+        collect(records);
+        let halt = match stop {
+            Stop::Halt(Halt::Breakpoint) => match self.symbol_reached(connection.as_mut()) {
+                Some(met) => return self.criterion_met(met_after_halt.unwrap_or(met), logs),
+                None => Halt::Breakpoint,
+            },
+            Stop::Halt(halt) => halt,
+            Stop::Criterion(met) => return self.criterion_met(met, logs),
+        };
 
-                if isr_no == 3 {
-                    let return_address = core.read_core_reg::<u32>(LR)?;
-                    let hfsr = core.read_word_32(0xE000_ED2C)?;
+        let log = join_records(&logs);
+        let mut exit_code = None;
+        let reason = match halt {
+            // A halt which cannot be told apart from a failure must not pass the run
+            Halt::Breakpoint => match semihosting::exit_code(connection.as_mut()) {
+                Ok(Some(code)) => {
+                    exit_code = Some(code);
+                    (code != 0).then_some(RunFailure::ExitCode { code })
+                }
+                // Such as a hardware breakpoint the firmware is not expected to reach
+                Ok(None) => Some(RunFailure::Halted {
+                    reason: "breakpoint outside of a bkpt instruction".into(),
+                }),
+                Err(RunnerError::Failed(reason, _)) => Some(reason),
+                Err(e) => Some(RunFailure::Other {
+                    error: format!("Unable to read the exit code: {}", error_chain(&e)),
+                }),
+            },
+            Halt::Exit(0) => {
+                exit_code = Some(0);
+                None
+            }
+            Halt::Exit(code) => Some(RunFailure::ExitCode { code }),
+            Halt::HardFault(fault) => Some(RunFailure::HardFault {
+                status: fault.status,
+                lr: fault.lr,
+                backtrace: match self.firmware.primary.format {
+                    ImageFormat::Elf => {
+                        backtrace::backtrace(&self.firmware.primary.binary, connection.as_mut())
+                            .unwrap_or_else(|e| {
+                                warn!("{}: Unable to unwind the stack: {}", self.probe_serial, e);
+                                Vec::new()
+                            })
+                    }
+                    // There is no debug information to unwind with
+                    _ => Vec::new(),
+                },
+            }),
+            Halt::Other(reason) => Some(RunFailure::Halted { reason }),
+            Halt::LockedUp => Some(RunFailure::LockedUp),
+        };
+        // A failing halt takes precedence over a criterion only met after it
+        if let Some(reason) = reason {
+            debug!("{}: {}, partial log:\n{}", self.probe_serial, reason, log);
+            return Err(RunnerError::Failed(reason, logs));
+        }
+        if let Some(met) = met_after_halt {
+            return self.criterion_met(met, logs);
+        }
 
-                    warn!("{}: Halted due to hardfault", self.probe_serial);
-                    if hfsr & (1 << 30) != 0 {
-                        let cfsr = core.read_word_32(0xE000_ED28)?;
+        debug!(
+            "{}: Log complete, size = {} bytes. Log:\n{}",
+            self.probe_serial,
+            log.len(),
+            log
+        );
 
-                        let mut report = String::new();
+        Ok(RunOutput { logs, exit_code })
+    }
 
-                        let mmfsr = (cfsr & 0xff) as u8;
-                        let bfsr = ((cfsr >> 8) & 0xff) as u8;
-                        let ufsr = ((cfsr >> 16) & 0xffff) as u16;
+    /// Whether the target already holds the images with `digest`: they were the last ones flashed
+    /// through the probe, and samples of them still read back the same.
+    fn holds_images(&self, connection: &mut dyn Connection, digest: u64) -> bool {
+        // Firmwares running from RAM do not survive the reset before the run
+        if self.firmware.from_ram || !self.flash_cache.holds(self.probe_serial, digest) {
+            return false;
+        }
+        match flash_cache::samples_match(connection, &self.firmware.info) {
+            Ok(matches) => matches,
+            Err(e) => {
+                debug!(
+                    "{}: Unable to read back the images: {}",
+                    self.probe_serial, e
+                );
+                false
+            }
+        }
+    }
 
-                        report.push_str(&format!("  LR = {:#04x}\n", return_address));
+    /// The exit criterion met by the core halting on a breakpoint, if any.
+    fn symbol_reached(&self, connection: &mut dyn Connection) -> Option<Met<'_>> {
+        if self.firmware.breakpoints.is_empty() {
+            return None;
+        }
+        match connection.read_core_register(CoreRegister::R(15)) {
+            Ok(pc) => self.exit_criteria.check_halt(pc),
+            Err(e) => {
+                warn!("{}: Unable to read the PC: {}", self.probe_serial, e);
+                None
+            }
+        }
+    }
 
-                        if mmfsr != 0 {
-                            report.push_str(&format!("  MemFault ({:#04x})\n", mmfsr));
-                        }
+    /// Fail with [`RunnerError::Cancelled`] when the run was cancelled before reaching `stage`.
+    fn check_cancelled(&self, cancel_flag: &AtomicBool, stage: &str) -> Result<(), RunnerError> {
+        if cancel_flag.load(Ordering::Relaxed) {
+            debug!("{}: Cancelled {}", self.probe_serial, stage);
+            return Err(RunnerError::Cancelled(Vec::new()));
+        }
+        Ok(())
+    }
 
-                        if bfsr != 0 {
-                            report.push_str(&format!("  BusFault ({:#04x})\n", bfsr));
-                            if bfsr & 0x80 != 0 {
-                                let bfar = core.read_word_32(0xE000_ED38)?;
-                                report
-                                    .push_str(&format!("    Offending address = {:#010x}\n", bfar));
-                            }
-                        }
+    /// End the run according to the exit criterion that was met.
+    fn criterion_met(&self, met: Met, logs: Vec<LogRecord>) -> Result<RunOutput, RunnerError> {
+        let log = join_records(&logs);
+        if let Some(reason) = met.failure() {
+            debug!("{}: {}, partial log:\n{}", self.probe_serial, reason, log);
+            return Err(RunnerError::Failed(reason, logs));
+        }
 
-                        if ufsr != 0 {
-                            report.push_str(&format!("  UsageFault ({:#06x})\n", ufsr));
-                        }
+        debug!(
+            "{}: Exit criterion met: {}. Log:\n{}",
+            self.probe_serial,
+            met.condition(),
+            log
+        );
+        Ok(RunOutput {
+            logs,
+            exit_code: None,
+        })
+    }
 
-                        return Err(anyhow!(
-                            "Core halted for hardfault\n{}\nPartial log:\n{}",
-                            report,
-                            log
-                        )
-                        .into());
+    /// Pick the up channels to capture and give each of them a decoder.
+    ///
+    /// `defmt` logs are expected on the channel named `defmt`, or on channel 0 when no channel
+    /// has that name. All the other channels are decoded as plain text.
+    fn capture(&self, up_channels: Vec<RttChannelInfo>) -> Result<Vec<Channel<'_>>, RunnerError> {
+        let defmt_channel = up_channels
+            .iter()
+            .find(|channel| channel.name.as_deref() == Some("defmt"))
+            .or(up_channels.first())
+            .map(|channel| channel.number);
+
+        let selected = match self.rtt_channels {
+            None => up_channels,
+            Some(selection) => {
+                let mut selected = Vec::new();
+                for choice in selection {
+                    let channel = find_channel(&up_channels, choice)?;
+                    if !selected.contains(channel) {
+                        selected.push(channel.clone());
                     }
+                }
+                selected
+            }
+        };
 
-                    return Err(anyhow!(
-                        "Core halted for hardfault (LR = {:#010x}), partial log:\n{}",
-                        return_address,
-                        log
-                    )
-                    .into());
+        Ok(selected
+            .into_iter()
+            .map(|channel| Channel {
+                number: channel.number,
+                label: channel.name.unwrap_or_else(|| channel.number.to_string()),
+                decoder: if Some(channel.number) == defmt_channel {
+                    LogDecoder::new(&self.rtt_type, self.probe_serial)
                 } else {
-                    debug!("{}: Halted due to breakpoint", self.probe_serial);
+                    LogDecoder::plain_text()
+                },
+            })
+            .collect())
+    }
+}
+
+/// The channel among `channels` chosen by `choice`.
+fn find_channel<'c>(
+    channels: &'c [RttChannelInfo],
+    choice: &RttChannel,
+) -> Result<&'c RttChannelInfo, RunnerError> {
+    channels
+        .iter()
+        .find(|channel| match choice {
+            RttChannel::Number(number) => channel.number == *number,
+            RttChannel::Name(name) => channel.name.as_ref() == Some(name),
+        })
+        .ok_or_else(|| {
+            RunnerError::Failed(
+                RunFailure::RttChannelNotFound {
+                    channel: choice.to_string(),
+                },
+                Vec::new(),
+            )
+        })
+}
+
+/// An RTT up channel being captured.
+struct Channel<'a> {
+    number: usize,
+    /// Name of the channel, its number when it has none.
+    label: String,
+    decoder: LogDecoder<'a>,
+}
+
+impl Channel<'_> {
+    fn label(&self, records: Vec<LogRecord>) -> Vec<LogRecord> {
+        records
+            .into_iter()
+            .map(|record| LogRecord {
+                channel: Some(self.label.clone()),
+                ..record
+            })
+            .collect()
+    }
+}
+
+/// Read whatever is pending on each of the `channels`, returns the records completed by it
+/// along with the number of bytes read.
+fn read_channels(
+    channels: &mut [Channel],
+    connection: &mut dyn Connection,
+    buffer: &mut [u8],
+) -> Result<(Vec<LogRecord>, usize), RunnerError> {
+    let mut records = Vec::new();
+    let mut received = 0;
+    for channel in channels {
+        let count = connection.read_rtt(channel.number, buffer)?;
+        let decoded = channel.decoder.feed(&buffer[..count]);
+        records.extend(channel.label(decoded));
+        received += count;
+    }
+    Ok((records, received))
+}
+
+/// Flush what is left in the decoders of the `channels`.
+fn finish_channels(channels: &mut [Channel]) -> Vec<LogRecord> {
+    let mut records = Vec::new();
+    for channel in channels {
+        let decoded = channel.decoder.finish();
+        records.extend(channel.label(decoded));
+    }
+    records
+}
+
+/// Flatten an error and its sources into a single line.
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut s = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        s.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    s
+}
+
+/// Render the records the way they would be printed by a terminal.
+fn join_records(records: &[LogRecord]) -> String {
+    records
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Incremental decoder turning raw RTT bytes into log records as they arrive.
+enum LogDecoder<'a> {
+    Defmt {
+        decoder: Box<dyn defmt_decoder::StreamDecoder + 'a>,
+        locations: &'a DefmtLocations,
+        can_recover: bool,
+        probe_serial: &'a ProbeSerial,
+        aborted: bool,
+    },
+    PlainText {
+        pending: Vec<u8>,
+    },
+}
+
+impl<'a> LogDecoder<'a> {
+    fn new(rtt_type: &'a RttType, probe_serial: &'a ProbeSerial) -> Self {
+        match rtt_type {
+            RttType::Defmt { table, locations } => {
+                debug!("{}: Detected defmt log", probe_serial);
+                LogDecoder::Defmt {
+                    decoder: table.new_stream_decoder(),
+                    locations,
+                    can_recover: table.encoding().can_recover(),
+                    probe_serial,
+                    aborted: false,
                 }
             }
-            CoreStatus::Halted(h) => {
-                return Err(anyhow!("Core halted for unknown reason: {:?}", h).into());
-            }
-            CoreStatus::LockedUp => {
-                return Err(anyhow!("Core locked up, partial log:\n{}", log).into());
-            }
-            CoreStatus::Sleeping => {
-                return Err(anyhow!("Core sleeping, partial log:\n{}", log).into());
-            }
-            CoreStatus::Unknown => {
-                return Err(anyhow!("Core status unknown, partial log:\n{}", log).into());
+            RttType::PlainText => {
+                debug!("{}: Plain-text log detected", probe_serial);
+                Self::plain_text()
             }
-            _ => {}
         }
+    }
 
-        debug!(
-            "{}: Log complete, size = {} bytes. Log:\n{}",
-            self.probe_serial,
-            log.len(),
-            log
-        );
-
-        Ok(logs)
+    fn plain_text() -> Self {
+        LogDecoder::PlainText {
+            pending: Vec::new(),
+        }
     }
 
-    /// Convert a raw log from a target to an actual readable format.
-    fn log_to_strings(&mut self, buffer: Vec<u8>) -> Result<Vec<String>, RunnerError> {
-        Ok(match &self.rtt_type {
-            RttType::Defmt {
-                table,
-                _locations: _,
+    /// Decode the next chunk of the log, returns the records completed by it.
+    fn feed(&mut self, bytes: &[u8]) -> Vec<LogRecord> {
+        let mut log = Vec::new();
+        match self {
+            LogDecoder::Defmt {
+                decoder,
+                locations,
+                can_recover,
+                probe_serial,
+                aborted,
             } => {
-                debug!(
-                    "{}: Detected defmt log - decoding, buffer size = {} bytes",
-                    self.probe_serial,
-                    buffer.len()
-                );
-
-                let mut stream_decoder = table.new_stream_decoder();
-                stream_decoder.received(&buffer);
-
-                let mut log = Vec::new();
+                if *aborted || bytes.is_empty() {
+                    return log;
+                }
+                decoder.received(bytes);
 
                 loop {
-                    match stream_decoder.decode() {
+                    match decoder.decode() {
                         Ok(frame) => {
-                            let level = match frame.level() {
-                                Some(level) => format!("{:<5} ", level.as_str().to_uppercase()),
-                                None => String::new(),
-                            };
-
-                            log.push(format!("{}{}", level, frame.display_message()));
+                            let location = locations.get(&frame.index());
+                            log.push(LogRecord {
+                                // `defmt_decoder` does not export its level type
+                                level: frame.level().and_then(|level| match level.as_str() {
+                                    "trace" => Some(LogLevel::Trace),
+                                    "debug" => Some(LogLevel::Debug),
+                                    "info" => Some(LogLevel::Info),
+                                    "warn" => Some(LogLevel::Warn),
+                                    "error" => Some(LogLevel::Error),
+                                    _ => None,
+                                }),
+                                timestamp: frame.display_timestamp().map(|t| t.to_string()),
+                                message: frame.display_message().to_string(),
+                                file: location.map(|l| l.file.display().to_string()),
+                                line: location.map(|l| l.line as u32),
+                                module_path: location.map(|l| l.module.clone()),
+                                channel: None,
+                            });
                         }
                         Err(DecodeError::Malformed) => {
-                            if table.encoding().can_recover() {
+                            if *can_recover {
                                 continue;
                             } else {
-                                warn!("{}: defmt stream is malformed, aborting", self.probe_serial);
-                                return Ok(log);
+                                warn!("{}: defmt stream is malformed, aborting", probe_serial);
+                                *aborted = true;
+                                break;
                             }
                         }
                         Err(DecodeError::UnexpectedEof) => {
@@ -494,95 +756,31 @@ impl<'a> Runner<'a> {
                         }
                     }
                 }
-
-                log
-            }
-            RttType::PlainText => {
-                debug!(
-                    "{}: Plain-text log detected - decoding, buffer size = {} bytes",
-                    self.probe_serial,
-                    buffer.len()
-                );
-
-                String::from_utf8(buffer)
-                    .map_err(|e| anyhow!(e))?
-                    .split('\n')
-                    .map(|v| v.into())
-                    .collect()
             }
-        })
-    }
-
-    /// Helper function to set up RTT channels and compensate for common errors.
-    fn setup_rtt_channel(&mut self, session: &mut Session) -> Result<UpChannel, RunnerError> {
-        debug!("{}: Starting RTT pipe", self.probe_serial);
-        let memory_map = session.target().memory_map.clone();
-        let mut core = session.core(0)?;
-        let start = Instant::now();
-
-        let mut rtt = loop {
-            match Rtt::attach_region(
-                &mut core,
-                &memory_map,
-                &ScanRegion::Exact(self.symbols.rtt.0),
-            ) {
-                Ok(rtt) => break rtt,
-                Err(RttError::ControlBlockNotFound) => {
-                    thread::sleep(Duration::from_millis(10));
-                    if Instant::now() - start > Duration::from_secs(3) {
-                        return Err(RttError::ControlBlockNotFound.into());
-                    }
+            LogDecoder::PlainText { pending } => {
+                pending.extend_from_slice(bytes);
+                if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+                    let complete: Vec<u8> = pending.drain(..=end).collect();
+                    log.extend(
+                        String::from_utf8_lossy(&complete[..end])
+                            .split('\n')
+                            .map(LogRecord::plain),
+                    );
                 }
-                Err(e) => return Err(e.into()),
             }
-        };
-
-        let channel = rtt
-            .up_channels()
-            .take(0)
-            .ok_or(anyhow!("Could not open the RTT channel"))?;
-
-        Ok(channel)
+        }
+        log
     }
 
-    /// Get this runner's probe.
-    fn get_probe(
-        &self,
-        probe_mutex: &Arc<Mutex<()>>,
-        probe_speed_khz: Option<u32>,
-    ) -> Result<Probe, RunnerError> {
-        // Access to the list of probes needs to be unique, else the workers crash into each other.
-        let guard = probe_mutex.lock().unwrap();
-        let probe = {
-            let all_probes = Probe::list_all();
-            let mut probe = all_probes
-                .iter()
-                .find(|probe| {
-                    if let Some(serial) = &probe.serial_number {
-                        &self.probe_serial.0 == serial
-                    } else {
-                        false
-                    }
-                })
-                .ok_or(DebugProbeError::ProbeCouldNotBeCreated(
-                    ProbeCreationError::NotFound,
-                ))?
-                .open()?;
-
-            if let Some(khz) = probe_speed_khz {
-                if let Err(e) = probe.set_speed(khz) {
-                    error!(
-                        "{}; Unable to set probe speed, error: {}",
-                        self.probe_serial,
-                        unroll_error(&e)
-                    );
-                }
+    /// Flush what is left once the target has stopped.
+    fn finish(&mut self) -> Vec<LogRecord> {
+        match self {
+            LogDecoder::Defmt { .. } => Vec::new(),
+            LogDecoder::PlainText { pending } => {
+                vec![LogRecord::plain(String::from_utf8_lossy(&std::mem::take(
+                    pending,
+                )))]
             }
-
-            probe
-        };
-        drop(guard);
-
-        Ok(probe)
+        }
     }
 }
@@ -0,0 +1,68 @@
+//! Exit statuses signalled by a firmware halting on a `bkpt` instruction.
+//!
+//! `bkpt 0xab` is a semihosting call, `SYS_EXIT` and `SYS_EXIT_EXTENDED` carry the exit status.
+//! Any other immediate is the exit code itself, so `bkpt 0` (as used by `exit()` in the test
+//! firmwares) signals success.
+//!
+//! Other semihosting calls, such as the `SYS_WRITE0` of `hprintln!`, are not serviced. Halting
+//! on one of them fails the run rather than passing it.
+
+use crate::backend::{Connection, CoreRegister};
+use crate::runner::RunnerError;
+use embedded_ci_common::job::RunFailure;
+
+const BKPT_MASK: u16 = 0xff00;
+const BKPT_OPCODE: u16 = 0xbe00;
+const SEMIHOSTING_IMMEDIATE: u16 = 0xab;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+/// `ADP_Stopped_ApplicationExit`, the reason of a regular exit.
+const APPLICATION_EXIT: u32 = 0x20026;
+const THUMB_BIT: u32 = 1;
+
+/// Exit code signalled by the firmware halted on a breakpoint.
+///
+/// Returns `None` when the core did not halt on a `bkpt` instruction, and
+/// [`RunFailure::Semihosting`] when it halted on a semihosting call other than an exit.
+pub fn exit_code(connection: &mut dyn Connection) -> Result<Option<i32>, RunnerError> {
+    let pc = connection.read_core_register(CoreRegister::R(15))? & !THUMB_BIT;
+    let mut instruction = [0u8; 2];
+    connection.read_memory(pc, &mut instruction)?;
+    let instruction = u16::from_le_bytes(instruction);
+    if instruction & BKPT_MASK != BKPT_OPCODE {
+        return Ok(None);
+    }
+
+    let immediate = instruction & !BKPT_MASK;
+    if immediate != SEMIHOSTING_IMMEDIATE {
+        return Ok(Some(immediate as i32));
+    }
+
+    let operation = connection.read_core_register(CoreRegister::R(0))?;
+    let parameter = connection.read_core_register(CoreRegister::R(1))?;
+    match operation {
+        // The parameter is the reason itself on 32-bit targets
+        SYS_EXIT => Ok(Some(exit_code_from_reason(parameter, 0))),
+        // The parameter points to the reason followed by the exit code
+        SYS_EXIT_EXTENDED => {
+            let mut block = [0u8; 8];
+            connection.read_memory(parameter, &mut block)?;
+            let reason = u32::from_le_bytes(block[..4].try_into().unwrap());
+            let subcode = u32::from_le_bytes(block[4..].try_into().unwrap());
+            Ok(Some(exit_code_from_reason(reason, subcode as i32)))
+        }
+        operation => Err(RunnerError::Failed(
+            RunFailure::Semihosting { operation },
+            Vec::new(),
+        )),
+    }
+}
+
+/// Exit code of an exit with `reason`, mirroring what QEMU does for the same calls.
+fn exit_code_from_reason(reason: u32, subcode: i32) -> i32 {
+    if reason == APPLICATION_EXIT {
+        subcode
+    } else {
+        1
+    }
+}
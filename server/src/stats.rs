@@ -0,0 +1,103 @@
+//! Usage of each target accumulated across restarts, to keep track of the wear of their flash.
+
+use crate::backend::FlashReport;
+use embedded_ci_common::{ProbeSerial, TargetStats};
+use log::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Persistent usage statistics of the targets.
+///
+/// Backed by a JSON file holding the statistics of every target, rewritten after each run.
+pub struct TargetStatsStore {
+    path: Option<PathBuf>,
+    stats: HashMap<ProbeSerial, TargetStats>,
+    /// Flash operations after which a target is due for replacement.
+    flash_operations_warning: Option<u64>,
+}
+
+impl TargetStatsStore {
+    /// Open the store at `path`, it is created on the first recorded run if it does not exist.
+    pub fn open(path: &Path, flash_operations_warning: Option<u64>) -> anyhow::Result<Self> {
+        let stats = match path.exists() {
+            true => serde_json::from_slice(&fs::read(path)?)?,
+            false => HashMap::new(),
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            stats,
+            flash_operations_warning,
+        })
+    }
+
+    /// A store that is not persisted.
+    #[cfg(test)]
+    pub fn in_memory(flash_operations_warning: Option<u64>) -> Self {
+        Self {
+            path: None,
+            stats: HashMap::new(),
+            flash_operations_warning,
+        }
+    }
+
+    /// Statistics of the target behind the probe, all zero when it has never been used.
+    pub fn get(&self, probe_serial: &ProbeSerial) -> TargetStats {
+        let mut stats = self.stats.get(probe_serial).cloned().unwrap_or_default();
+        stats.replacement_due = self.replacement_due(&stats);
+        stats
+    }
+
+    /// Record a run of `run_time` on the target behind the probe, `flash_report` being what
+    /// flashing did when the flash was written to.
+    pub fn record_run(
+        &mut self,
+        probe_serial: &ProbeSerial,
+        flash_report: Option<FlashReport>,
+        run_time: Duration,
+    ) {
+        let stats = self.stats.entry(probe_serial.clone()).or_default();
+        stats.runs += 1;
+        stats.run_time_ms += run_time.as_millis() as u64;
+        if let Some(report) = flash_report {
+            stats.flash_operations += 1;
+            stats.bytes_programmed += report.bytes_programmed;
+            stats.sectors_erased += report.sectors_erased;
+            if self.flash_operations_warning == Some(stats.flash_operations) {
+                warn!(
+                    "{}: flashed {} times, the target is due for replacement",
+                    probe_serial, stats.flash_operations
+                );
+            }
+        }
+
+        if let Err(e) = self.save() {
+            error!("Failed to persist the target stats: {}", e);
+        }
+    }
+
+    fn replacement_due(&self, stats: &TargetStats) -> bool {
+        self.flash_operations_warning
+            .is_some_and(|warning| stats.flash_operations >= warning)
+    }
+
+    /// Write the statistics next to the store and move them over it, so that a crash never
+    /// leaves a truncated store behind.
+    fn save(&self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".tmp");
+        let tmp_path = path.with_file_name(file_name);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&serde_json::to_vec_pretty(&self.stats)?)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
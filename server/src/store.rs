@@ -0,0 +1,331 @@
+//! Jobs and their results persisted across restarts, jobs interrupted by a restart are queued
+//! again.
+
+use embedded_ci_common::{job, Uuid};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A single entry of the append-only job log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// Job has been accepted by the REST API.
+    ///
+    /// [`job::Image::binary`] is not serialized as part of the [`job::Job`], thus the binaries are
+    /// stored alongside, one per image of each task in order.
+    Enqueued {
+        job: job::Job,
+        binaries_b64: Vec<String>,
+    },
+    /// Job has been picked up by the scheduler.
+    Started { id: Uuid },
+    /// Job has finished (or has been cancelled) and its result is available.
+    Finished { result: job::JobResult },
+    /// Job result has been dropped from the finished queue.
+    Cleared { id: Uuid },
+}
+
+/// State of a job reconstructed by replaying the log.
+enum Entry {
+    Queued(job::Job),
+    Running(job::Job),
+    Finished(job::JobResult),
+}
+
+/// Jobs reloaded from the store on startup.
+pub struct RestoredJobs {
+    /// Jobs which were queued or interrupted while running, in the order they were enqueued.
+    pub queued: Vec<job::Job>,
+    /// Results of finished jobs, oldest first.
+    pub finished: Vec<job::JobResult>,
+}
+
+/// Persistent storage of job descriptions, statuses and results.
+///
+/// Backed by a file with one JSON record per line. Records are only ever appended, the file is
+/// compacted down to the live jobs each time it is opened.
+pub struct JobStore {
+    file: Option<File>,
+}
+
+impl JobStore {
+    /// Open the store at `path`, creating it if it does not exist.
+    ///
+    /// Returns the store together with the jobs that were still alive when the server stopped.
+    pub fn open(path: &Path) -> anyhow::Result<(Self, RestoredJobs)> {
+        let mut order = Vec::new();
+        let mut entries = HashMap::new();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => replay(record, &mut order, &mut entries),
+                    // Most likely the server went down in the middle of writing a record
+                    Err(e) => warn!(
+                        "Skipping malformed record at {}:{}: {}",
+                        path.display(),
+                        index + 1,
+                        e
+                    ),
+                }
+            }
+        }
+
+        let mut restored = RestoredJobs {
+            queued: Vec::new(),
+            finished: Vec::new(),
+        };
+        for id in order {
+            match entries.remove(&id) {
+                Some(Entry::Queued(job)) => restored.queued.push(job),
+                Some(Entry::Running(job)) => {
+                    warn!("{}: interrupted while running, re-queueing", job.id);
+                    restored.queued.push(job)
+                }
+                Some(Entry::Finished(result)) => restored.finished.push(result),
+                None => {}
+            }
+        }
+
+        let compacted_path = compacted_path(path);
+        {
+            let mut compacted = File::create(&compacted_path)?;
+            for job in restored.queued.iter() {
+                write_record(&mut compacted, &enqueued_record(job))?;
+            }
+            for result in restored.finished.iter() {
+                write_record(
+                    &mut compacted,
+                    &Record::Finished {
+                        result: result.clone(),
+                    },
+                )?;
+            }
+            compacted.sync_all()?;
+        }
+        fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        Ok((Self { file: Some(file) }, restored))
+    }
+
+    /// A store that is not persisted.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self { file: None }
+    }
+
+    /// Records a job accepted by the REST API
+    pub fn job_enqueued(&mut self, job: &job::Job) {
+        self.append(&enqueued_record(job));
+    }
+
+    /// Records a job picked up by the scheduler
+    pub fn job_started(&mut self, id: Uuid) {
+        self.append(&Record::Started { id });
+    }
+
+    /// Records the result of a finished job
+    pub fn job_finished(&mut self, result: &job::JobResult) {
+        self.append(&Record::Finished {
+            result: result.clone(),
+        });
+    }
+
+    /// Records a job result dropped from the finished queue
+    pub fn job_cleared(&mut self, id: Uuid) {
+        self.append(&Record::Cleared { id });
+    }
+
+    fn append(&mut self, record: &Record) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        if let Err(e) = write_record(file, record).and_then(|_| file.sync_data()) {
+            error!("Failed to persist the job record: {}", e);
+        }
+    }
+}
+
+fn replay(record: Record, order: &mut Vec<Uuid>, entries: &mut HashMap<Uuid, Entry>) {
+    match record {
+        Record::Enqueued {
+            mut job,
+            binaries_b64,
+        } => {
+            let image_count: usize = job.tasks.iter().map(|task| task.images.len()).sum();
+            if image_count != binaries_b64.len() {
+                warn!(
+                    "{}: {} stored binaries for {} images, dropping",
+                    job.id,
+                    binaries_b64.len(),
+                    image_count
+                );
+                return;
+            }
+            let images = job.tasks.iter_mut().flat_map(|task| task.images.iter_mut());
+            for (image, binary_b64) in images.zip(binaries_b64.iter()) {
+                match base64::decode(binary_b64) {
+                    Ok(binary) => image.binary = binary,
+                    Err(e) => {
+                        warn!("{}: stored binary is corrupted, dropping: {}", job.id, e);
+                        return;
+                    }
+                }
+            }
+            order.push(job.id);
+            entries.insert(job.id, Entry::Queued(job));
+        }
+        Record::Started { id } => {
+            if let Some(Entry::Queued(job)) = entries.remove(&id) {
+                entries.insert(id, Entry::Running(job));
+            }
+        }
+        Record::Finished { result } => {
+            if !entries.contains_key(&result.id) {
+                order.push(result.id);
+            }
+            entries.insert(result.id, Entry::Finished(result));
+        }
+        Record::Cleared { id } => {
+            entries.remove(&id);
+        }
+    }
+}
+
+fn enqueued_record(job: &job::Job) -> Record {
+    Record::Enqueued {
+        job: job.clone(),
+        binaries_b64: job
+            .tasks
+            .iter()
+            .flat_map(|task| task.images.iter())
+            .map(|image| base64::encode(&image.binary))
+            .collect(),
+    }
+}
+
+fn write_record(file: &mut File, record: &Record) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+fn compacted_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".compact");
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(image_count: usize) -> job::Job {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "tasks": [{
+                "id": Uuid::new_v4(),
+                "targets": [],
+                "images": vec![serde_json::json!({}); image_count],
+            }],
+            "timeout": { "secs": 5, "nanos": 0 },
+        }))
+        .unwrap()
+    }
+
+    fn enqueued(job: &job::Job, binaries: &[&[u8]]) -> Record {
+        Record::Enqueued {
+            job: job.clone(),
+            binaries_b64: binaries.iter().map(base64::encode).collect(),
+        }
+    }
+
+    fn queued_ids(restored: &RestoredJobs) -> Vec<Uuid> {
+        restored.queued.iter().map(|job| job.id).collect()
+    }
+
+    fn finished_ids(restored: &RestoredJobs) -> Vec<Uuid> {
+        restored.finished.iter().map(|result| result.id).collect()
+    }
+
+    fn finished(job: &job::Job) -> Record {
+        Record::Finished {
+            result: job::JobResult::empty_from_job(job),
+        }
+    }
+
+    #[test]
+    fn live_jobs_are_restored() {
+        let path = std::env::temp_dir().join(format!("job-store-{}.jsonl", Uuid::new_v4()));
+        let passed = job(1);
+        let interrupted = job(2);
+        let cleared = job(1);
+        let queued = job(1);
+        let mismatched = job(2);
+        let failed = job(1);
+        let truncated = job(1);
+        {
+            let mut file = File::create(&path).unwrap();
+            let records = [
+                enqueued(&passed, &[b"passed"]),
+                Record::Started { id: passed.id },
+                enqueued(&interrupted, &[b"first", b"second"]),
+                finished(&passed),
+                Record::Started { id: interrupted.id },
+                enqueued(&cleared, &[b"cleared"]),
+                Record::Started { id: cleared.id },
+                finished(&cleared),
+                Record::Cleared { id: cleared.id },
+                enqueued(&queued, &[b"queued"]),
+                enqueued(&mismatched, &[b"only one"]),
+                enqueued(&failed, &[b"failed"]),
+                Record::Started { id: failed.id },
+                finished(&failed),
+            ];
+            for record in records.iter() {
+                write_record(&mut file, record).unwrap();
+            }
+            // The server went down while writing the last record
+            let line = serde_json::to_string(&enqueued(&truncated, &[b"truncated"])).unwrap();
+            file.write_all(&line.as_bytes()[..line.len() / 2]).unwrap();
+        }
+
+        let (_, restored) = JobStore::open(&path).unwrap();
+        assert_eq!(queued_ids(&restored), &[interrupted.id, queued.id]);
+        let binaries: Vec<_> = restored.queued[0].tasks[0]
+            .images
+            .iter()
+            .map(|image| image.binary.as_slice())
+            .collect();
+        assert_eq!(binaries, &[&b"first"[..], &b"second"[..]]);
+        assert_eq!(finished_ids(&restored), &[passed.id, failed.id]);
+
+        // The store is compacted down to the live jobs, which survive another restart
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 4);
+        let (mut store, restored) = JobStore::open(&path).unwrap();
+        assert_eq!(restored.queued.len(), 2);
+        assert_eq!(restored.queued[1].tasks[0].images[0].binary, b"queued");
+        assert_eq!(restored.finished.len(), 2);
+
+        store.job_started(queued.id);
+        store.job_cleared(passed.id);
+        drop(store);
+        let (_, restored) = JobStore::open(&path).unwrap();
+        assert_eq!(queued_ids(&restored), &[interrupted.id, queued.id]);
+        assert_eq!(finished_ids(&restored), &[failed.id]);
+        fs::remove_file(path).unwrap();
+    }
+}